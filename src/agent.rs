@@ -28,6 +28,9 @@ use env_logger;
 
 use std::env;
 use std::fs;
+use std::process;
+use std::path::Path;
+use serde_json::Value;
 
 mod application;
 
@@ -40,8 +43,14 @@ mod alert;
 mod dracarys;
 mod maester;
 mod nightfort;
+mod inventory;
 mod knight;
 mod ranger;
+mod spool;
+mod cron;
+mod statsd;
+mod exec;
+mod watch;
 mod eval;
 mod dispatcher;
 mod raven;
@@ -51,17 +60,116 @@ use utils::*;
 use ranger::Ranger;
 
 
+const USAGE: &str = "Usage: nw-ranger [-c=CONFIG] [--validate] [--once [--json] [--threshold=N]] [--target NAME]
+       nw-ranger exec [-c=CONFIG] --leaf PATH [--every SECONDS | --cron EXPR] [--grace SECONDS] [--tail LINES] -- COMMAND [ARGS]
+
+    -c=CONFIG       config file, ./config.json by default
+    --validate      check the config and report problems, then exit
+    --once          run the targets a single time and print the results, then exit
+    --json          print the results of --once as json
+    --threshold=N   a check fails with health at or below N, the fail_health of the target by default
+    --target NAME   only the target with the name or id, with --validate or --once";
+
+fn load_config(path: &str) -> Result<Value, String> {
+    let conf_file = fs::File::open(path).map_err(|e| format!("Failed to read config file {}, error: {}", path, e))?;
+    serde_json::from_reader(conf_file).map_err(|e| format!("Failed to parse config file {}, error: {}", path, e))
+}
+
+// Print the results of a single run, returns the number of failed checks
+fn report(results: &Vec<Value>, threshold: Option<u64>, as_json: bool) -> usize {
+    let failed = |result: &Value| !result["error"].is_null() ||
+        result["health"].as_u64().map(|health| health <= threshold.unwrap_or(result.get_u64("fail_health", 1))).unwrap_or(false);
+    if as_json {
+        println!("{}", serde_json::to_string_pretty(results).unwrap());
+    } else {
+        println!("{:<40} {:>6} {:>8}  {}", "TARGET", "HEALTH", "TIME", "RESULT");
+        for result in results.iter() {
+            let health = match result["health"].as_u64() {
+                Some(health) => health.to_string(),
+                None => "-".to_string(),
+            };
+            let status = if failed(result) { "FAILED" } else { "OK" };
+            println!("{:<40} {:>6} {:>6}ms  {}", result["key"].as_str().unwrap_or(""), health, result["elapsed"].as_u64().unwrap_or(0), status);
+            if let Some(error) = result["error"].as_str() {
+                println!("    error: {}", error);
+            }
+            for metric in result["metrics"].as_array().unwrap_or(&Vec::new()).iter() {
+                println!("    {} = {}", metric["path"].as_str().unwrap_or(""), metric["value"].as_str().unwrap_or(""));
+            }
+            for message in result["messages"].as_array().unwrap_or(&Vec::new()).iter() {
+                println!("    message: {}", message.as_str().unwrap_or(""));
+            }
+        }
+    }
+    results.iter().filter(|result| failed(result)).count()
+}
+
 #[tokio::main]
 async fn main() -> AsyncRes {
     env_logger::init();
     let mut conf_path = None;
+    let mut validate = false;
+    let mut once = false;
+    let mut as_json = false;
+    let mut threshold = None;
+    let mut target = None;
 
-    for arg in env::args().skip(1) {
-        if arg.starts_with("-c") {
+    // Wrap a job and report its result
+    let all: Vec<String> = env::args().skip(1).collect();
+    if all.first().map(|arg| arg == "exec").unwrap_or(false) {
+        let job = match exec::Job::parse(&all[1..]) {
+            Ok(job) => job,
+            Err(e) => {
+                eprintln!("{}\n{}", e, exec::USAGE);
+                process::exit(2);
+            }
+        };
+        let conf = job.conf_path.clone().unwrap_or("./config.json".to_string());
+        // Config is optional for exec, the default nightfort is used without it
+        let map = if job.conf_path.is_none() && !Path::new(&conf).exists() {
+            json!({})
+        } else {
+            load_config(&conf).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            })
+        };
+        process::exit(exec::run(&map, job).await);
+    }
+
+    let mut args = all.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--validate" {
+            validate = true;
+        } else if arg == "--once" {
+            once = true;
+        } else if arg == "--json" {
+            as_json = true;
+        } else if arg.starts_with("--threshold=") {
+            threshold = Some(arg.split_at(12).1.parse().unwrap_or_else(|_| {
+                eprintln!("Invalid threshold: {}", arg);
+                process::exit(2);
+            }));
+        } else if arg == "--target" {
+            target = args.next();
+        } else if arg.starts_with("--target=") {
+            target = Some(arg.split_at(9).1.to_string());
+        } else if arg == "-h" || arg == "--help" {
+            println!("{}", USAGE);
+            return Ok(());
+        } else if arg.starts_with("-c=") {
             conf_path = Some(arg.split_at(3).1.to_string());
+        } else {
+            eprintln!("Unknown argument: {}\n{}", arg, USAGE);
+            process::exit(2);
         }
     }
 
+    if target.is_some() && !validate && !once {
+        eprintln!("--target only works with --validate or --once\n{}", USAGE);
+        process::exit(2);
+    }
+
     let conf = if let Some(path) = conf_path {
         path
     } else {
@@ -69,9 +177,35 @@ async fn main() -> AsyncRes {
     };
     info!("Loading configuration from {}", conf);
 
-    let conf_file = fs::File::open(conf).expect("Failed to read config file");
-    let map = serde_json::from_reader(conf_file).expect("Failed to parse config file");
-    let ranger = Ranger::new(&map);
+    let map = load_config(&conf).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+
+    if validate || once {
+        let mut targets = ranger::Map::new(&map);
+        if let Some(ref name) = target {
+            targets.select(name);
+            if targets.map.is_empty() && targets.problems.is_empty() {
+                eprintln!("No target named {} in {}", name, conf);
+                process::exit(2);
+            }
+        }
+        if validate {
+            for problem in targets.problems.iter() {
+                println!("{}", problem);
+            }
+            println!("{}: {} targets, {} problems", conf, targets.map.len(), targets.problems.len());
+            if !targets.problems.is_empty() { process::exit(1); }
+        }
+        if once {
+            let failed = report(&targets.check_once().await, threshold, as_json);
+            if failed > 0 || targets.map.is_empty() { process::exit(1); }
+        }
+        return Ok(());
+    }
+
+    let ranger = Ranger::new(&map, &conf);
     ranger.start().await?;
     warn!("This ranger is being destroyed!!!");
     Ok(())
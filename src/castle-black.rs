@@ -40,9 +40,11 @@ mod alert;
 mod dracarys;
 mod maester;
 mod nightfort;
+mod inventory;
 mod eval;
 mod dispatcher;
 mod raven;
+mod checkin;
 
 use utils::*;
 
@@ -51,6 +53,7 @@ use nightfort::Nightfort;
 use log::{info, warn};
 use std::sync::Arc;
 use maester::Maester;
+use checkin::Checkin;
 
 #[tokio::main]
 async fn main() -> AsyncRes {
@@ -84,6 +87,12 @@ async fn main() -> AsyncRes {
     let mut nightfort = Nightfort::new(&global_watcher);
     maester.add_watcher(&global_watcher);
     tokio::spawn( async move { let _ = nightfort.setup().await; } );
+    let checkin = Checkin::new(&global_watcher);
+    tokio::spawn( async move {
+        if let Err(e) = checkin.setup().await {
+            error!("Failed to start check-in endpoint, error: {}", e);
+        }
+    });
     tokio::spawn( async move { let _ = maester.setup().await; } );
 
     watcher.start().await?;
@@ -0,0 +1,263 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use serde_json::Value;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::*;
+use tokio::time;
+use std::time::Duration;
+use crate::utils::{self, JsonParser, AsyncRes};
+use crate::watcher::Watcher;
+use crate::node::Node;
+
+// Sample configuration in castle-black config
+//
+// checkin_listen_bind: 0.0.0.0:6080
+// checkins:
+//  - token: 3f2a9c0e         # secret part of the url
+//    path: .app.jobs.backup  # leaf created at startup, its parent should exist
+//    period: 86400           # seconds between expected check-ins
+//    grace: 600              # extra seconds before a missing check-in degrades the leaf
+//    display_name: Nightly backup
+//
+// Check-in with GET or POST to /ping/<token>, or /ping/<token>/fail to report a failure right
+// away. The request body, if any, is kept as the last message of the leaf. The leaf stays down
+// until the first check-in, so that a job never checking in is noticed as well.
+//
+
+const MAX_REQUEST_BYTES: usize = 65536;
+
+struct CheckinTarget {
+    parent: String,
+    name: String,
+    threshold: u64,
+    raw: Value,
+    leaf: Mutex<Weak<Node>>,
+}
+
+pub struct Checkin {
+    watcher: Weak<Watcher>,
+    listen_bind: Option<String>,
+    targets: Arc<HashMap<String, CheckinTarget>>,
+}
+
+// Status line and body of the response
+type Response = (&'static str, String);
+
+impl Checkin {
+    pub fn new(watcher: &Arc<Watcher>) -> Checkin {
+        let (listen_bind, raw) = {
+            let landing = watcher.landing.read().unwrap();
+            (landing.checkin_listen_bind.clone(), landing.checkins.clone())
+        };
+        let mut targets = HashMap::new();
+        for item in raw.as_array().unwrap_or(&Vec::new()).iter() {
+            let token = item.get_str("token", "");
+            let path = item.get_str("path", "");
+            let (parent, name) = match path.rfind('.') {
+                Some(pos) if pos > 0 && pos + 1 < path.len() => (path[..pos].to_string(), path[pos+1..].to_string()),
+                _ => {
+                    error!("Invalid check-in path {}, expecting like .app.jobs.backup", path);
+                    continue;
+                }
+            };
+            if token.is_empty() {
+                error!("Token of check-in {} is missing", path);
+                continue;
+            }
+            let threshold = item.get_u64("period", 3600) + item.get_u64("grace", 300);
+            let mut raw = item.clone();
+            raw["health_report_threshold"] = json!(threshold);
+            if raw["display_name"].is_null() { raw["display_name"] = json!(name); }
+            targets.insert(token, CheckinTarget {
+                parent,
+                name,
+                threshold,
+                raw,
+                leaf: Mutex::new(Weak::new()),
+            });
+        }
+        Checkin {
+            watcher: Arc::downgrade(watcher),
+            listen_bind,
+            targets: Arc::new(targets),
+        }
+    }
+
+    pub async fn setup(&self) -> AsyncRes {
+        let listen_bind = match self.listen_bind {
+            Some(ref listen_bind) => listen_bind.clone(),
+            None => return Ok(()),
+        };
+        let addr: SocketAddr = listen_bind.parse()?;
+        let mut listener = TcpListener::bind(&addr).await?;
+        info!("Check-in endpoint listening on {} with {} check-ins", addr, self.targets.len());
+        if let Some(watcher) = self.watcher.upgrade() {
+            self.prepare(&watcher);
+        }
+        loop {
+            match listener.accept().await {
+                Ok((stream, addr)) => {
+                    let watcher = self.watcher.clone();
+                    let targets = self.targets.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = Self::serve(watcher, targets, stream, addr).await {
+                            warn!("Failed to serve check-in from {}, error: {}", addr, e);
+                        }
+                    });
+                },
+                Err(e) => error!("Failed to accept check-in connection, error: {}", e),
+            }
+        }
+    }
+
+    async fn serve(watcher: Weak<Watcher>, targets: Arc<HashMap<String, CheckinTarget>>, mut stream: TcpStream, addr: SocketAddr) -> AsyncRes {
+        let mut buf = Vec::new();
+        let mut chunk = [0u8; 4096];
+        // Read the head and the body with content length
+        let (head, body) = loop {
+            let size = time::timeout(Duration::from_secs(10), stream.read(&mut chunk)).await??;
+            if size == 0 { return Ok(()); }
+            buf.extend_from_slice(&chunk[..size]);
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&buf[..pos]).to_string();
+                let length = head.lines()
+                    .filter_map(|line| {
+                        let mut parts = line.splitn(2, ':');
+                        match (parts.next(), parts.next()) {
+                            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case("content-length") => value.trim().parse::<usize>().ok(),
+                            _ => None,
+                        }
+                    })
+                    .next()
+                    .unwrap_or(0)
+                    .min(MAX_REQUEST_BYTES);
+                while buf.len() < pos + 4 + length {
+                    let size = time::timeout(Duration::from_secs(10), stream.read(&mut chunk)).await??;
+                    if size == 0 { break; }
+                    buf.extend_from_slice(&chunk[..size]);
+                }
+                let end = buf.len().min(pos + 4 + length);
+                break (head, String::from_utf8_lossy(&buf[pos+4..end]).to_string());
+            }
+            if buf.len() > MAX_REQUEST_BYTES {
+                return Ok(());
+            }
+        };
+
+        let mut request = head.lines().next().unwrap_or("").split_whitespace();
+        let method = request.next().unwrap_or("");
+        let path = request.next().unwrap_or("");
+        let (status, message) = match method {
+            "GET" | "POST" | "HEAD" => Self::checkin(&watcher, &targets, path, &body, &addr),
+            _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+        };
+        let mut response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", status, message.len());
+        if method != "HEAD" { response.push_str(&message); }
+        stream.write_all(response.as_bytes()).await?;
+        Ok(())
+    }
+
+    fn checkin(watcher: &Weak<Watcher>, targets: &HashMap<String, CheckinTarget>, path: &str, body: &str, addr: &SocketAddr) -> Response {
+        let path = path.split('?').next().unwrap_or("");
+        let mut parts = path.trim_start_matches('/').split('/');
+        let (token, fail) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some("ping"), Some(token), None, None) => (token, false),
+            (Some("ping"), Some(token), Some("fail"), None) => (token, true),
+            _ => return ("404 Not Found", "Not found\n".to_string()),
+        };
+        let target = match targets.get(token) {
+            Some(target) => target,
+            None => {
+                warn!("Check-in with unknown token from {}", addr);
+                return ("404 Not Found", "Unknown check-in\n".to_string());
+            }
+        };
+        let watcher = match watcher.upgrade() {
+            Some(watcher) => watcher,
+            None => return ("503 Service Unavailable", "Shutting down\n".to_string()),
+        };
+        let node = match Self::locate(&watcher, target) {
+            Some(node) => node,
+            None => {
+                error!("Failed to create check-in leaf {}.{}, does the parent exist?", target.parent, target.name);
+                return ("500 Internal Server Error", "Failed to create the leaf\n".to_string());
+            }
+        };
+        let mut state = node.write().unwrap();
+        state.health_status = if fail { 0 } else { 100 };
+        state.health_flags = 0;
+        state.health_last_report = utils::now();
+        state.health_report_threshold = target.threshold.min(std::u32::MAX as u64) as u32;
+        let message = body.trim();
+        state.health_last_message = if message.is_empty() {
+            utils::tidings("checkin", &format!("Check-in {} from {}", if fail { "failure" } else { "success" }, addr.ip()))
+        } else {
+            utils::tidings("checkin", message)
+        };
+        info!("Check-in {} for {}.{} from {}", if fail { "failure" } else { "success" }, target.parent, target.name, addr);
+        ("200 OK", "OK\n".to_string())
+    }
+
+    // Create the leaves of the check-ins, down until the first check-in
+    fn prepare(&self, watcher: &Arc<Watcher>) {
+        for target in self.targets.values() {
+            let path = format!("{}.{}", target.parent, target.name);
+            if watcher.locate_node(&path).is_some() { continue; }
+            let node = match Self::locate(watcher, target) {
+                Some(node) => node,
+                None => {
+                    error!("Failed to create check-in leaf {}, does the parent exist?", path);
+                    continue;
+                }
+            };
+            let mut state = node.write().unwrap();
+            state.health_status = 0;
+            state.health_last_report = utils::now();
+            state.health_report_threshold = target.threshold.min(std::u32::MAX as u64) as u32;
+            state.health_last_message = utils::tidings("checkin", "Waiting for the first check-in");
+        }
+    }
+
+    // Leaf of the check-in, created at startup or on the first check-in
+    fn locate(watcher: &Arc<Watcher>, target: &CheckinTarget) -> Option<Arc<Node>> {
+        let mut leaf = target.leaf.lock().unwrap();
+        if let Some(node) = leaf.upgrade() {
+            return Some(node);
+        }
+        let path = format!("{}.{}", target.parent, target.name);
+        let node = match watcher.locate_node(&path) {
+            Some(node) => node,
+            None => {
+                if watcher.locate_node(&target.parent).is_none() { return None; }
+                info!("Creating check-in leaf {}", path);
+                watcher.allocate_ranger(&target.name, &vec![target.parent.clone()], &target.raw)?
+            }
+        };
+        *leaf = node.clone();
+        node.upgrade()
+    }
+}
@@ -0,0 +1,169 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+
+// Cron schedule in local time with the five standard fields
+//
+//   minute hour day-of-month month day-of-week
+//
+// Fields accept `*`, values, ranges `1-5`, steps `*/15` or `0-30/10` and lists of them, day of
+// week is 0-7 with both 0 and 7 for Sunday. When both day fields are restricted a day matching
+// either of them is scheduled. @hourly, @daily, @weekly, @monthly and @yearly are shortcuts.
+//
+
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut values = vec![false; max as usize + 1];
+    for item in field.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(pos) => {
+                let step = item[pos+1..].parse::<u32>().map_err(|_| format!("Invalid step in {}", item))?;
+                if step == 0 { return Err(format!("Invalid step in {}", item)); }
+                (&item[..pos], step)
+            },
+            None => (item, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(pos) = range.find('-') {
+            let start = range[..pos].parse::<u32>().map_err(|_| format!("Invalid range {}", range))?;
+            let end = range[pos+1..].parse::<u32>().map_err(|_| format!("Invalid range {}", range))?;
+            (start, end)
+        } else {
+            let value = range.parse::<u32>().map_err(|_| format!("Invalid value {}", range))?;
+            // A single value with a step runs from the value to the end
+            if item.contains('/') { (value, max) } else { (value, value) }
+        };
+        if start < min || end > max || start > end {
+            return Err(format!("Value out of range {}-{} in {}", min, max, item));
+        }
+        let mut value = start;
+        while value <= end {
+            values[value as usize] = true;
+            value += step;
+        }
+    }
+    Ok(values)
+}
+
+impl Schedule {
+    pub fn parse(expr: &str) -> Result<Schedule, String> {
+        let expr = match expr.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            expr => expr,
+        };
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Cron expression should have 5 fields: {}", expr));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        if weekdays[7] { weekdays[0] = true; }
+        Ok(Schedule {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+
+    fn day_matches(&self, time: &NaiveDateTime) -> bool {
+        let day = self.days[time.day() as usize];
+        let weekday = self.weekdays[time.weekday().num_days_from_sunday() as usize];
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+
+    // First scheduled time strictly after the timestamp, in seconds
+    pub fn next_after(&self, ts: u64) -> Option<u64> {
+        let start = Local.timestamp_opt(ts as i64, 0).single()?.naive_local();
+        let mut time = start.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        // Give up after about four years of minutes, an expression like Feb 31 never matches
+        let limit = start + Duration::days(4 * 366);
+        while time < limit {
+            if !self.months[time.month() as usize] {
+                let (year, month) = if time.month() == 12 { (time.year() + 1, 1) } else { (time.year(), time.month() + 1) };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.day_matches(&time) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !self.hours[time.hour() as usize] {
+                time = time.with_minute(0)? + Duration::hours(1);
+            } else if !self.minutes[time.minute() as usize] {
+                time = time + Duration::minutes(1);
+            } else {
+                // Skip the local times that do not exist on daylight saving changes
+                if let Some(local) = Local.from_local_datetime(&time).earliest() {
+                    let next = local.timestamp() as u64;
+                    if next > ts { return Some(next); }
+                }
+                time = time + Duration::minutes(1);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cron_schedule() {
+        let local = |y, mo, d, h, mi| Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap().timestamp() as u64;
+        let schedule = Schedule::parse("30 3 * * *").unwrap();
+        assert_eq!(schedule.next_after(local(2020, 1, 1, 0, 0)), Some(local(2020, 1, 1, 3, 30)));
+        assert_eq!(schedule.next_after(local(2020, 1, 1, 3, 30)), Some(local(2020, 1, 2, 3, 30)));
+
+        let schedule = Schedule::parse("*/15 9-17 * * 1-5").unwrap();
+        // 2020-01-04 is a Saturday
+        assert_eq!(schedule.next_after(local(2020, 1, 4, 12, 0)), Some(local(2020, 1, 6, 9, 0)));
+        assert_eq!(schedule.next_after(local(2020, 1, 6, 9, 1)), Some(local(2020, 1, 6, 9, 15)));
+
+        let schedule = Schedule::parse("@monthly").unwrap();
+        assert_eq!(schedule.next_after(local(2020, 12, 15, 0, 0)), Some(local(2021, 1, 1, 0, 0)));
+
+        assert!(Schedule::parse("0 3 * *").is_err());
+        assert!(Schedule::parse("61 * * * *").is_err());
+        assert!(Schedule::parse("0 0 31 2 *").unwrap().next_after(local(2020, 1, 1, 0, 0)).is_none());
+    }
+}
@@ -33,19 +33,53 @@ use crate::utils;
 // 0xe001  Target
 // 0xe002  Report
 // 0xe003  Message
+// 0xe004  Metric
+// 0xe005  Retire
+// 0xe006  Error
+// 0xe007  Command
+// 0xe008  Ack
+// 0xe009  Hello
+// 0xe00a  Config
+// 0xe00b  Heartbeat
 
 
-#[derive(Debug)]
+// Flags of a report
+pub const REPORT_FLAPPING: u8 = 0x01;
+// Check skipped as a dependency of the target is down
+pub const REPORT_DEPENDENCY_FAILED: u8 = 0x02;
+// Report queued while disconnected and replayed after reconnect, only kept as history
+pub const REPORT_REPLAYED: u8 = 0x04;
+
+// Commands from nightfort to ranger for a target
+#[derive(Debug, Clone, PartialEq)]
+pub enum RangerCommand {
+    RunNow,
+    Pause,
+    Resume,
+    // Check interval in seconds for a duration in seconds, 0 interval to restore and 0 duration
+    // to keep until next change
+    SetInterval {
+        interval: u32,
+        duration: u32,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub enum Dracarys {
     Target {
         id: u16,
         paths: Vec<String>,
         name: String,
         extra: String,
+        // Stable identity of the target, empty for older rangers
+        key: String,
     },
     Report {
         id: u16,
         health_status: u8,
+        // Time of the check, 0 from older rangers
+        timestamp: u64,
+        flags: u8,
     },
     Message {
         id: u16,
@@ -55,7 +89,36 @@ pub enum Dracarys {
         id: u16,
         relative: bool,
         metrics: Vec<(String, String, u64)>,
-    }
+    },
+    Retire {
+        id: u16,
+    },
+    Error {
+        id: u16,
+        message: String,
+    },
+    Command {
+        id: u16,
+        seq: u32,
+        command: RangerCommand,
+    },
+    Ack {
+        id: u16,
+        seq: u32,
+        success: bool,
+        message: String,
+    },
+    // Identity of the ranger in json, sent first on connection
+    Hello {
+        data: String,
+    },
+    // Targets pushed from nightfort in json, with u32 length for large target lists
+    Config {
+        data: String,
+    },
+    Heartbeat {
+        uptime: u64,
+    },
 }
 
 #[derive(Clone)]
@@ -66,6 +129,20 @@ impl DracarysFramer {
     pub fn new() -> DracarysFramer {
         DracarysFramer {}
     }
+
+    // Fields with u16 length and lists with u8 count
+    fn fits(msg: &Dracarys) -> bool {
+        let max = std::u16::MAX as usize;
+        match *msg {
+            Dracarys::Target { ref paths, ref name, ref extra, ref key, .. } =>
+                paths.len() <= 255 && paths.iter().all(|p| p.len() <= max) && name.len() <= max && extra.len() <= max && key.len() <= max,
+            Dracarys::Metric { ref metrics, .. } =>
+                metrics.len() <= 255 && metrics.iter().all(|m| m.0.len() <= max && m.1.len() <= max),
+            Dracarys::Message { ref data, .. } | Dracarys::Hello { ref data } => data.len() <= max,
+            Dracarys::Error { ref message, .. } | Dracarys::Ack { ref message, .. } => message.len() <= max,
+            _ => true,
+        }
+    }
 }
 
 impl codec::Encoder for DracarysFramer {
@@ -74,15 +151,20 @@ impl codec::Encoder for DracarysFramer {
 
     fn encode(&mut self, msg: Dracarys, res: &mut bytes::BytesMut) -> Result<(), io::Error> {
         info!("Sending message: {:?}", msg);
+        // Length over the field size would break the framing of the following frames
+        if !Self::fits(&msg) {
+            error!("Dropped frame with a field over the size limit");
+            return Ok(());
+        }
         match msg {
-            Dracarys::Target { id, ref paths, ref name, ref extra} => {
+            Dracarys::Target { id, ref paths, ref name, ref extra, ref key } => {
                 let path_count = paths.len();
                 let mut path_total_len: usize = 0;
                 for path in paths.iter() {
                     path_total_len += path.len();
                 }
-                let data_len = path_total_len + name.len() + extra.len();
-                let total_len = 8 + data_len + 4 + 1 + path_count * 2;
+                let data_len = path_total_len + name.len() + extra.len() + key.len();
+                let total_len = 8 + data_len + 6 + 1 + path_count * 2;
                 res.reserve(total_len);
                 res.put_u16_le(0xe001);
                 res.put_u32_le(total_len as u32);
@@ -96,21 +178,26 @@ impl codec::Encoder for DracarysFramer {
                 res.put_slice(name.as_bytes());
                 res.put_u16_le(extra.len() as u16);
                 res.put_slice(extra.as_bytes());
+                res.put_u16_le(key.len() as u16);
+                res.put_slice(key.as_bytes());
             },
-            Dracarys::Report { id, health_status } => {
-                let total_len = 8 + 1;
+            Dracarys::Report { id, health_status, timestamp, flags } => {
+                let total_len = 8 + 1 + 8 + 1;
                 res.reserve(total_len);
                 res.put_u16_le(0xe002);
                 res.put_u32_le(total_len as u32);
                 res.put_u16_le(id);
                 res.put_u8(health_status);
+                res.put_u64_le(timestamp);
+                res.put_u8(flags);
             },
             Dracarys::Message { id, ref data } => {
-                let total_len = 8 + data.len();
+                let total_len = 8 + 2 + data.len();
                 res.reserve(total_len);
                 res.put_u16_le(0xe003);
                 res.put_u32_le(total_len as u32);
                 res.put_u16_le(id);
+                res.put_u16_le(data.len() as u16);
                 res.put_slice(data.as_bytes());
             },
             Dracarys::Metric { id, relative, ref metrics } => {
@@ -134,6 +221,76 @@ impl codec::Encoder for DracarysFramer {
                     res.put_u64_le(m.2);
                 }
             },
+            Dracarys::Retire { id } => {
+                let total_len = 8;
+                res.reserve(total_len);
+                res.put_u16_le(0xe005);
+                res.put_u32_le(total_len as u32);
+                res.put_u16_le(id);
+            },
+            Dracarys::Error { id, ref message } => {
+                let total_len = 8 + 2 + message.len();
+                res.reserve(total_len);
+                res.put_u16_le(0xe006);
+                res.put_u32_le(total_len as u32);
+                res.put_u16_le(id);
+                res.put_u16_le(message.len() as u16);
+                res.put_slice(message.as_bytes());
+            },
+            Dracarys::Command { id, seq, ref command } => {
+                let (action, interval, duration) = match *command {
+                    RangerCommand::RunNow => (1, 0, 0),
+                    RangerCommand::Pause => (2, 0, 0),
+                    RangerCommand::Resume => (3, 0, 0),
+                    RangerCommand::SetInterval { interval, duration } => (4, interval, duration),
+                };
+                let total_len = 8 + 4 + 1 + 4 + 4;
+                res.reserve(total_len);
+                res.put_u16_le(0xe007);
+                res.put_u32_le(total_len as u32);
+                res.put_u16_le(id);
+                res.put_u32_le(seq);
+                res.put_u8(action);
+                res.put_u32_le(interval);
+                res.put_u32_le(duration);
+            },
+            Dracarys::Ack { id, seq, success, ref message } => {
+                let total_len = 8 + 4 + 1 + 2 + message.len();
+                res.reserve(total_len);
+                res.put_u16_le(0xe008);
+                res.put_u32_le(total_len as u32);
+                res.put_u16_le(id);
+                res.put_u32_le(seq);
+                res.put_u8(success as u8);
+                res.put_u16_le(message.len() as u16);
+                res.put_slice(message.as_bytes());
+            },
+            Dracarys::Hello { ref data } => {
+                let total_len = 8 + 2 + data.len();
+                res.reserve(total_len);
+                res.put_u16_le(0xe009);
+                res.put_u32_le(total_len as u32);
+                res.put_u16_le(0);
+                res.put_u16_le(data.len() as u16);
+                res.put_slice(data.as_bytes());
+            },
+            Dracarys::Config { ref data } => {
+                let total_len = 8 + 4 + data.len();
+                res.reserve(total_len);
+                res.put_u16_le(0xe00a);
+                res.put_u32_le(total_len as u32);
+                res.put_u16_le(0);
+                res.put_u32_le(data.len() as u32);
+                res.put_slice(data.as_bytes());
+            },
+            Dracarys::Heartbeat { uptime } => {
+                let total_len = 8 + 8;
+                res.reserve(total_len);
+                res.put_u16_le(0xe00b);
+                res.put_u32_le(total_len as u32);
+                res.put_u16_le(0);
+                res.put_u64_le(uptime);
+            },
         }
         Ok(())
     }
@@ -193,22 +350,36 @@ impl codec::Decoder for DracarysFramer {
                 }
                 let name = read_string!();
                 let extra = read_string!();
+                // Key is only sent by newer rangers
+                let key = if pos < len { read_string!() } else { String::new() };
                 msg = Dracarys::Target {
                     id,
                     paths,
                     name,
                     extra,
+                    key,
                 };
-                bytes.advance(pos);
+                bytes.advance(len);
             },
             0xe002 => {
                 let health_status = bytes[pos] as u8; 
                 pos += 1;
+                let mut timestamp = 0;
+                let mut flags = 0;
+                if pos + 8 <= len {
+                    timestamp = utils::get_u64_le(&bytes[pos..pos+8]);
+                    pos += 8;
+                }
+                if pos < len {
+                    flags = bytes[pos];
+                }
                 msg = Dracarys::Report {
                     id,
                     health_status,
+                    timestamp,
+                    flags,
                 };
-                bytes.advance(pos);
+                bytes.advance(len);
             },
             0xe003 => {
                 let data = read_string!();
@@ -232,6 +403,80 @@ impl codec::Decoder for DracarysFramer {
                 };
                 bytes.advance(pos);
             },
+            0xe005 => {
+                bytes.advance(pos);
+                msg = Dracarys::Retire { id };
+            },
+            0xe006 => {
+                let message = read_string!();
+                bytes.advance(pos);
+                msg = Dracarys::Error { id, message };
+            },
+            0xe007 => {
+                if len < pos + 13 {
+                    error!("Failed to decode message: {:?}", bytes);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, utils::CodecError));
+                }
+                let seq = utils::get_u32_le(&bytes[pos..pos+4]);
+                let action = bytes[pos+4];
+                let interval = utils::get_u32_le(&bytes[pos+5..pos+9]);
+                let duration = utils::get_u32_le(&bytes[pos+9..pos+13]);
+                let command = match action {
+                    1 => RangerCommand::RunNow,
+                    2 => RangerCommand::Pause,
+                    3 => RangerCommand::Resume,
+                    4 => RangerCommand::SetInterval { interval, duration },
+                    _ => {
+                        error!("Failed to decode command for unknown action: {}", action);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, utils::CodecError));
+                    }
+                };
+                bytes.advance(len);
+                msg = Dracarys::Command { id, seq, command };
+            },
+            0xe008 => {
+                if len < pos + 5 {
+                    error!("Failed to decode message: {:?}", bytes);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, utils::CodecError));
+                }
+                let seq = utils::get_u32_le(&bytes[pos..pos+4]);
+                let success = bytes[pos+4] > 0;
+                pos += 5;
+                let message = read_string!();
+                bytes.advance(pos);
+                msg = Dracarys::Ack { id, seq, success, message };
+            },
+            0xe009 => {
+                let data = read_string!();
+                bytes.advance(len);
+                msg = Dracarys::Hello { data };
+            },
+            0xe00a => {
+                if len < pos + 4 || len < pos + 4 + utils::get_u32_le(&bytes[pos..pos+4]) as usize {
+                    error!("Failed to decode message: {:?}", bytes);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, utils::CodecError));
+                }
+                let size = utils::get_u32_le(&bytes[pos..pos+4]) as usize;
+                pos += 4;
+                let data = match String::from_utf8(bytes[pos..pos+size].to_vec()) {
+                    Ok(data) => data,
+                    Err(_) => {
+                        error!("Failed to decode message: {:?}", bytes);
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, utils::CodecError));
+                    },
+                };
+                bytes.advance(len);
+                msg = Dracarys::Config { data };
+            },
+            0xe00b => {
+                if len < pos + 8 {
+                    error!("Failed to decode message: {:?}", bytes);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, utils::CodecError));
+                }
+                let uptime = utils::get_u64_le(&bytes[pos..pos+8]);
+                bytes.advance(len);
+                msg = Dracarys::Heartbeat { uptime };
+            },
 
             _ => {
                 error!("Failed to decode message for unknown flag: {:?}", flag);
@@ -244,3 +489,22 @@ impl codec::Decoder for DracarysFramer {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::{Encoder, Decoder};
+
+    #[test]
+    fn test_oversized_frame() {
+        let mut framer = DracarysFramer::new();
+        let mut data = bytes::BytesMut::new();
+        framer.encode(Dracarys::Message { id: 1, data: "x".repeat(70000) }, &mut data).unwrap();
+        framer.encode(Dracarys::Message { id: 2, data: "ok".to_string() }, &mut data).unwrap();
+        // Following frames are still decoded
+        match framer.decode(&mut data).unwrap() {
+            Some(Dracarys::Message { id, data }) => assert_eq!((id, data.as_str()), (2, "ok")),
+            _ => panic!("Unexpected frame"),
+        }
+    }
+}
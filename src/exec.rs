@@ -0,0 +1,321 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use std::collections::VecDeque;
+use std::os::unix::process::ExitStatusExt;
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Instant;
+use serde_json::Value;
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use std::io::Write;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio_util::codec::Framed;
+use futures::SinkExt;
+use crate::utils::{self, JsonParser};
+use crate::dracarys::{Dracarys, DracarysFramer};
+use crate::cron::Schedule;
+use crate::ranger::Map;
+
+// Wrap a job like a cron job, and report its result as the health of a leaf
+//
+//   nw-ranger exec [-c=CONFIG] --leaf .app.jobs.backup [--every SECONDS | --cron "0 3 * * *"]
+//     [--grace SECONDS] [--tail LINES] -- command args
+//
+// The output of the command is passed through, and the command exit code is kept as the exit
+// code. Nightfort addresses, hostname and labels are taken from the ranger config. With the
+// expected schedule, the report threshold of the leaf is set to the time of the next run plus
+// grace, so that a missed or late run leaves the leaf without report and raises an alert.
+//
+
+pub const USAGE: &str = "Usage: nw-ranger exec [-c=CONFIG] --leaf PATH [--every SECONDS | --cron EXPR] [--grace SECONDS] [--tail LINES] -- COMMAND [ARGS]";
+
+pub struct Job {
+    pub conf_path: Option<String>,
+    parent: String,
+    name: String,
+    every: Option<u64>,
+    cron: Option<(String, Schedule)>,
+    grace: u64,
+    tail: usize,
+    command: Vec<String>,
+}
+
+impl Job {
+    pub fn parse(args: &[String]) -> Result<Job, String> {
+        let mut job = Job {
+            conf_path: None,
+            parent: String::new(),
+            name: String::new(),
+            every: None,
+            cron: None,
+            grace: 300,
+            tail: 20,
+            command: Vec::new(),
+        };
+        let mut args = args.iter();
+        let value = |arg: &str, value: Option<&String>| value.cloned().ok_or(format!("Missing value of {}", arg));
+        let number = |arg: &str, value: String| value.parse::<u64>().map_err(|_| format!("Invalid value of {}: {}", arg, value));
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--" => {
+                    job.command = args.cloned().collect();
+                    break;
+                },
+                "--leaf" => {
+                    let leaf = value(arg, args.next())?;
+                    match leaf.rfind('.') {
+                        Some(pos) if pos > 0 && pos + 1 < leaf.len() => {
+                            job.parent = leaf[..pos].to_string();
+                            job.name = leaf[pos+1..].to_string();
+                        },
+                        _ => return Err(format!("Invalid leaf path {}, expecting like .app.jobs.backup", leaf)),
+                    }
+                },
+                "--every" => job.every = Some(number(arg, value(arg, args.next())?)?),
+                "--cron" => {
+                    let expr = value(arg, args.next())?;
+                    job.cron = Some((expr.clone(), Schedule::parse(&expr)?));
+                },
+                "--grace" => job.grace = number(arg, value(arg, args.next())?)?,
+                "--tail" => job.tail = number(arg, value(arg, args.next())?)? as usize,
+                _ if arg.starts_with("-c=") => job.conf_path = Some(arg.split_at(3).1.to_string()),
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        if job.name.is_empty() { return Err("Leaf path is required".to_string()); }
+        if job.command.is_empty() { return Err("Command is required".to_string()); }
+        if job.every.is_some() && job.cron.is_some() { return Err("Only one of --every and --cron can be used".to_string()); }
+        Ok(job)
+    }
+
+    // Seconds until the report of the next run is late, the leaf never expires without a schedule
+    fn report_threshold(&self, now: u64) -> u64 {
+        if let Some(every) = self.every {
+            return every + self.grace;
+        }
+        if let Some((_, ref cron)) = self.cron {
+            if let Some(next) = cron.next_after(now) {
+                return next - now + self.grace;
+            }
+        }
+        std::u32::MAX as u64
+    }
+
+    fn key(&self) -> String {
+        format!("{}@{}", self.name, self.parent)
+    }
+
+    fn target(&self, now: u64) -> Dracarys {
+        let key = self.key();
+        Dracarys::Target {
+            id: utils::hash_u16(&key),
+            name: self.name.clone(),
+            paths: vec![self.parent.clone()],
+            extra: json!({
+                "display_name": self.name,
+                "description": format!("Job: {}", self.command.join(" ")),
+                "health_report_threshold": self.report_threshold(now),
+            }).to_string(),
+            key,
+        }
+    }
+}
+
+// Connection to nightfort kept for the whole run, so that the leaf is registered only once
+struct Reporter {
+    nightforts: Vec<String>,
+    hello: Value,
+    stream: Option<Framed<TcpStream, DracarysFramer>>,
+}
+
+impl Reporter {
+    async fn connect(&mut self, job: &Job) -> bool {
+        for nightfort in self.nightforts.iter() {
+            let mut stream = match TcpStream::connect(nightfort.as_str()).await {
+                Ok(stream) => Framed::new(stream, DracarysFramer::new()),
+                Err(e) => {
+                    warn!("Failed to connect to {}, error: {}", nightfort, e);
+                    continue;
+                }
+            };
+            if stream.send(Dracarys::Hello { data: self.hello.to_string() }).await.is_ok() &&
+                stream.send(job.target(utils::now())).await.is_ok() {
+                self.stream = Some(stream);
+                return true;
+            }
+        }
+        false
+    }
+
+    // Send the frames, reconnect once if the connection was lost during the run
+    async fn send(&mut self, job: &Job, frames: Vec<Dracarys>) {
+        for _ in 0..2 {
+            if self.stream.is_none() && !self.connect(job).await { break; }
+            let stream = self.stream.as_mut().unwrap();
+            let mut success = true;
+            for msg in frames.iter() {
+                if let Err(e) = stream.send(msg.clone()).await {
+                    warn!("Failed to report job {}, error: {}", job.key(), e);
+                    success = false;
+                    break;
+                }
+            }
+            if success { return; }
+            self.stream = None;
+        }
+        error!("Failed to report job {} to any of {:?}", job.key(), self.nightforts);
+    }
+}
+
+// Copy the raw output through as is, keep its lines for the report tail
+async fn pass_through<R: AsyncRead + Unpin, W: Write>(reader: R, mut out: W, keep: impl Fn(String)) {
+    let mut reader = BufReader::new(reader);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        match reader.read_until(b'\n', &mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let _ = out.write_all(&buf).and_then(|_| out.flush());
+                keep(String::from_utf8_lossy(&buf).trim_end_matches(&['\r', '\n'][..]).to_string());
+            }
+        }
+    }
+}
+
+pub async fn run(raw: &Value, job: Job) -> i32 {
+    let map = Map::new(&json!({ "nightfort": raw["nightfort"] }));
+    let mut reporter = Reporter {
+        nightforts: map.nightforts,
+        hello: json!({
+            "hostname": raw.get_str("hostname", &utils::hostname()),
+            "labels": raw["labels"],
+            "managed": false,
+            "version": env!("CARGO_PKG_VERSION"),
+            "pid": std::process::id(),
+            "started": utils::now(),
+            "exec": job.key(),
+        }),
+        stream: None,
+    };
+    let id = utils::hash_u16(&job.key());
+    let command = job.command.join(" ");
+    reporter.send(&job, vec![Dracarys::Message {
+        id,
+        data: json!({ "type": "job", "status": "started", "message": format!("Started job: {}", command) }).to_string(),
+    }]).await;
+
+    let start = Instant::now();
+    let started = utils::now();
+    let tail = Mutex::new(VecDeque::new());
+    let keep = |line: String| {
+        let mut tail = tail.lock().unwrap();
+        tail.push_back(line);
+        if tail.len() > job.tail { tail.pop_front(); }
+    };
+    let mut cmd = Command::new(&job.command[0]);
+    cmd.args(&job.command[1..]).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let exit_code = match cmd.spawn() {
+        Ok(mut child) => {
+            let stdout = child.stdout.take().unwrap();
+            let stderr = child.stderr.take().unwrap();
+            let read_stdout = pass_through(stdout, std::io::stdout(), &keep);
+            let read_stderr = pass_through(stderr, std::io::stderr(), &keep);
+            let (status, _, _) = futures::join!(child, read_stdout, read_stderr);
+            match status {
+                Ok(status) => status.code().unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+                Err(e) => {
+                    keep(format!("Failed to wait for the command, error: {}", e));
+                    127
+                }
+            }
+        },
+        Err(e) => {
+            let info = format!("Failed to run command {}, error: {}", command, e);
+            error!("{}", info);
+            keep(info);
+            127
+        }
+    };
+    let duration = start.elapsed().as_secs_f64();
+    let now = utils::now();
+    info!("Job {} finished with exit code {} in {:.3} seconds", job.key(), exit_code, duration);
+
+    let output: Vec<String> = tail.into_inner().unwrap().into_iter().collect();
+    let mut output = output.join("\n");
+    // Message frame holds at most 64KB
+    if output.len() > 8192 {
+        let mut pos = output.len() - 8192;
+        while !output.is_char_boundary(pos) { pos += 1; }
+        output = output.split_off(pos);
+    }
+    reporter.send(&job, vec![
+        Dracarys::Report {
+            id,
+            health_status: if exit_code == 0 { 100 } else { 0 },
+            timestamp: now,
+            flags: 0,
+        },
+        Dracarys::Metric {
+            id,
+            relative: true,
+            metrics: vec![
+                (".exit_code".to_string(), exit_code.to_string(), now),
+                (".duration".to_string(), format!("{:.3}", duration), now),
+                (".last_run".to_string(), started.to_string(), now),
+            ],
+        },
+        Dracarys::Message {
+            id,
+            data: json!({
+                "type": "job",
+                "status": if exit_code == 0 { "succeeded" } else { "failed" },
+                "message": format!("Job {} exited with code {} after {:.3} seconds", command, exit_code, duration),
+                "exit_code": exit_code,
+                "duration": duration,
+                "started": started,
+                "schedule": job.cron.as_ref().map(|cron| cron.0.clone()),
+                "every": job.every,
+                "output": output,
+            }).to_string(),
+        },
+    ]).await;
+    exit_code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_pass_through_binary() {
+        let input: &[u8] = b"ok\r\n\xff\xfe\x00bin\nlast";
+        let mut out = Vec::new();
+        let lines = Mutex::new(Vec::new());
+        pass_through(input, &mut out, |line| lines.lock().unwrap().push(line)).await;
+        assert_eq!(out, input);
+        assert_eq!(lines.into_inner().unwrap(), vec!["ok", "\u{fffd}\u{fffd}\u{0}bin", "last"]);
+    }
+}
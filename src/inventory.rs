@@ -0,0 +1,142 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::JsonParser;
+use std::fs;
+
+// Sample inventory of ranger targets, pushed to the rangers which connect with managed identity
+//
+// [
+//   {
+//     "hosts": ["web-1", "web-2"],     # match by hostname of the ranger
+//     "labels": { "role": "web" },     # or by labels, all of them have to match
+//     "targets": [
+//       { "name": "nginx", "paths": [".app.web"], "watch": { "type": "watch_tcp", "address": "127.0.0.1:80" } }
+//     ]
+//   }
+// ]
+//
+// Entries with both hosts and labels require both to match, entries with neither match any ranger.
+//
+
+struct Entry {
+    hosts: Vec<String>,
+    labels: Vec<(String, String)>,
+    targets: Vec<Value>,
+}
+
+impl Entry {
+    fn matches(&self, hostname: &str, labels: &Value) -> bool {
+        if !self.hosts.is_empty() && !self.hosts.iter().any(|host| host == hostname) {
+            return false;
+        }
+        self.labels.iter().all(|(key, value)| labels[key].as_str() == Some(value.as_str()))
+    }
+}
+
+pub struct Inventory {
+    entries: Vec<Entry>,
+}
+
+impl Inventory {
+    pub fn new(raw: &Value) -> Inventory {
+        let mut entries = Vec::new();
+        if let Some(items) = raw.as_array() {
+            for item in items.iter() {
+                let mut hosts = Vec::new();
+                if let Some(values) = item["hosts"].as_array() {
+                    for host in values.iter() {
+                        if let Some(host) = host.as_str() {
+                            hosts.push(host.to_string());
+                        }
+                    }
+                }
+                let mut labels = Vec::new();
+                if let Some(values) = item["labels"].as_object() {
+                    for (key, value) in values.iter() {
+                        if let Some(value) = value.as_str() {
+                            labels.push((key.clone(), value.to_string()));
+                        }
+                    }
+                }
+                entries.push(Entry {
+                    hosts,
+                    labels,
+                    targets: item["targets"].as_array().cloned().unwrap_or_default(),
+                });
+            }
+        }
+        Inventory { entries }
+    }
+
+    pub fn load(path: &str) -> Option<Inventory> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to read ranger inventory {}, error: {}", path, e);
+                return None;
+            }
+        };
+        match serde_json::from_str(&data) {
+            Ok(raw) => Some(Inventory::new(&raw)),
+            Err(e) => {
+                error!("Failed to parse ranger inventory {}, error: {}", path, e);
+                None
+            }
+        }
+    }
+
+    // Targets for the ranger identity from hello
+    pub fn targets(&self, hello: &Value) -> Vec<Value> {
+        let hostname = hello.get_str("hostname", "");
+        let mut targets = Vec::new();
+        for entry in self.entries.iter() {
+            if entry.matches(&hostname, &hello["labels"]) {
+                targets.extend(entry.targets.iter().cloned());
+            }
+        }
+        targets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inventory_selector() {
+        let inventory = Inventory::new(&json!([
+            { "hosts": ["web-1"], "targets": [{ "name": "a" }] },
+            { "labels": { "role": "web" }, "targets": [{ "name": "b" }] },
+            { "hosts": ["web-2"], "labels": { "dc": "east" }, "targets": [{ "name": "c" }] },
+            { "targets": [{ "name": "d" }] }
+        ]));
+        let names = |hello: Value| -> Vec<String> {
+            inventory.targets(&hello).iter().map(|t| t.get_str("name", "")).collect()
+        };
+        assert_eq!(names(json!({ "hostname": "web-1" })), vec!["a", "d"]);
+        assert_eq!(names(json!({ "hostname": "web-2", "labels": { "role": "web", "dc": "east" } })), vec!["b", "c", "d"]);
+        assert_eq!(names(json!({ "hostname": "web-2", "labels": { "dc": "west" } })), vec!["d"]);
+    }
+}
@@ -25,94 +25,67 @@ use tokio::{net::TcpStream };
 use tokio_util::codec::{ self, FramedRead, FramedWrite };
 use std::{io, marker, net::{SocketAddr, ToSocketAddrs}, marker::Unpin, sync::Arc };
 use crate::utils::AsyncRes;
-use futures::{Stream, join, SinkExt, StreamExt};
+use futures::{future, Stream, SinkExt, StreamExt};
 
 
 pub trait Wine<MessageType> {
     type Stream: Stream<Item=MessageType> + Send + Unpin;
     type Framer: codec::Encoder<Item=MessageType, Error=io::Error> + codec::Decoder<Item=MessageType, Error=io::Error> + Send + Unpin;
     fn wake_up(&self) -> Self::Stream;
-    fn take_nap(&self);
+    // Called with the stream of the lost connection, holding the messages not sent yet
+    fn take_nap(&self, stream: Self::Stream);
     fn drink(&self, message: MessageType);
     fn get_framer(&self) -> Self::Framer;
 }
 
 pub struct Knight<MessageType: 'static + Send, WineProvider: Wine<MessageType>> {
-    target: String,
+    // Targets in order of preference
+    targets: Vec<String>,
+    // Seconds between checks whether a preferred target is back, 0 to disable
+    failback_interval: u64,
     wine: WineProvider,
     ph: marker::PhantomData<&'static MessageType>,
 }
 
 impl<MessageType: Send + Sync, WineProvider: 'static + Wine<MessageType> + Send + Sync> Knight<MessageType, WineProvider> {
-    pub fn new(target: &String, wine: WineProvider) -> Knight<MessageType, WineProvider> {
+    pub fn new(targets: &Vec<String>, failback_interval: u64, wine: WineProvider) -> Knight<MessageType, WineProvider> {
         Knight {
-            target: target.clone(),
+            targets: targets.clone(),
+            failback_interval,
             wine,
             ph: marker::PhantomData,
         }
     }
 
     pub async fn drink_wine(self) -> AsyncRes {
+        if self.targets.is_empty() {
+            return Err("No target address to connect to".into());
+        }
         let us = Arc::new(self);
         loop {
             let mut success = false;
-            let mut target: Option<SocketAddr> = None;
-            us.parse_target(&mut target).await?;
-            if let Some(addr) = target {
-                info!("Connecting to remote target: {}", addr);
-                match TcpStream::connect(&addr).await {
-                    Ok(mut stream) => {
-                        let (r, w) = stream.split();
-                        let mut messenger = us.wine.wake_up();
-                        // let mut stream = Framed::new(stream, self.wine.get_framer());
-                        let mut tx = FramedWrite::new(w, us.wine.get_framer());
-                        let mut rx = FramedRead::new(r, us.wine.get_framer());
-                        success = true;
-                        // self.rx.forward(tx);
-                        // tx.send_all(self.tx);
-                        // self.rx.forward(stream);
-                        info!("Connected to {}", addr);
-                        let handler = us.clone();
-                        join!(async move {
-                                loop {
-                                    match rx.next().await {
-                                        Some(Ok(msg)) => { handler.wine.drink(msg); },
-                                        _ => {
-                                            warn!("Potential disconnection from remote side");
-                                            break;
-                                        },
-                                    }
-                                }
-                            },
-                            async {
-                                loop {
-                                    match messenger.next().await {
-                                        Some(msg) => {
-                                            match tx.send(msg).await {
-                                                Ok(_) => {
-                                                    // info!("Message sent");
-                                                }
-                                                Err(e) => {
-                                                    error!("Connection broken! error: {}", e);
-                                                    break;
-                                                },
-                                            }
-                                        },
-                                        _ => { break; },
-                                    }
-                                }
-                                us.wine.take_nap();
-                            }
-                        );
-                    },
-                    Err(e) => {
-                        error!("Failed to connect to {}, error: {:?}", addr, e);
-                    },
+            // Try the targets in order, so that the preferred one is used whenever available
+            for (index, target) in us.targets.iter().enumerate() {
+                let mut addrs = Vec::new();
+                us.parse_target(target, &mut addrs).await?;
+                for addr in addrs.iter() {
+                    info!("Connecting to remote target: {}", addr);
+                    match TcpStream::connect(addr).await {
+                        Ok(stream) => {
+                            success = true;
+                            us.serve(stream, addr, index).await;
+                            break;
+                        },
+                        Err(e) => {
+                            error!("Failed to connect to {}, error: {:?}", addr, e);
+                        },
+                    }
                 }
+                if success { break; }
             }
 
             if !success {
-                warn!("Faild to parse target address: {}", us.target);
+                warn!("Faild to connect to any of the target addresses: {:?}", us.targets);
             } else {
                 warn!("Disconnected from target address, will reconnect again after one second");
             }
@@ -120,10 +93,72 @@ impl<MessageType: Send + Sync, WineProvider: 'static + Wine<MessageType> + Send
         }
     }
 
-    async fn parse_target(&self, addr: &mut Option<SocketAddr>) -> AsyncRes {
-        match self.target.to_socket_addrs() {
-            Ok(mut addrs) => {
-                *addr = addrs.next();
+    async fn serve(self: &Arc<Self>, mut stream: TcpStream, addr: &SocketAddr, index: usize) {
+        let (r, w) = stream.split();
+        let mut messenger = self.wine.wake_up();
+        let mut tx = FramedWrite::new(w, self.wine.get_framer());
+        let mut rx = FramedRead::new(r, self.wine.get_framer());
+        info!("Connected to {}", addr);
+        let handler = self.clone();
+        tokio::select! {
+            _ = async move {
+                loop {
+                    match rx.next().await {
+                        Some(Ok(msg)) => { handler.wine.drink(msg); },
+                        _ => {
+                            warn!("Potential disconnection from remote side");
+                            break;
+                        },
+                    }
+                }
+            } => {},
+            _ = async {
+                loop {
+                    match messenger.next().await {
+                        Some(msg) => {
+                            match tx.send(msg).await {
+                                Ok(_) => {
+                                    // info!("Message sent");
+                                }
+                                Err(e) => {
+                                    error!("Connection broken! error: {}", e);
+                                    break;
+                                },
+                            }
+                        },
+                        _ => { break; },
+                    }
+                }
+            } => {},
+            _ = self.wait_failback(index) => {},
+        }
+        self.wine.take_nap(messenger);
+    }
+
+    // Resolves when a target preferred over the current one accepts connections again
+    async fn wait_failback(&self, index: usize) {
+        if index == 0 || self.failback_interval == 0 {
+            future::pending::<()>().await;
+        }
+        loop {
+            sleep!(1000 * self.failback_interval);
+            for target in self.targets[..index].iter() {
+                let mut addrs = Vec::new();
+                let _ = self.parse_target(target, &mut addrs).await;
+                for addr in addrs.iter() {
+                    if TcpStream::connect(addr).await.is_ok() {
+                        info!("Preferred target {} is available again, switching over", target);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn parse_target(&self, target: &String, addrs: &mut Vec<SocketAddr>) -> AsyncRes {
+        match target.to_socket_addrs() {
+            Ok(resolved) => {
+                addrs.extend(resolved);
             },
             _ => {}
         }
@@ -32,6 +32,11 @@ pub struct Landing {
     pub maester_listen_bind: String,
     pub redis_publish: Option<String>,
     pub watcher_tick_interval: usize,
+    // Json file of targets pushed to managed rangers, see inventory.rs
+    pub ranger_inventory: Option<String>,
+    // Http check-in endpoint and its check-ins, see checkin.rs
+    pub checkin_listen_bind: Option<String>,
+    pub checkins: Value,
 }
 
 
@@ -42,6 +47,9 @@ impl Landing {
             maester_listen_bind: "0.0.0.0:3012".to_string(),
             redis_publish: None,
             watcher_tick_interval: 10,
+            ranger_inventory: None,
+            checkin_listen_bind: None,
+            checkins: Value::Null,
         }
     }
 
@@ -63,5 +71,12 @@ impl Landing {
         if let Some(redis_publish) = raw["redis_publish"].as_str() {
             self.redis_publish = Some(redis_publish.to_string());
         }
+        if let Some(ranger_inventory) = raw["ranger_inventory"].as_str() {
+            self.ranger_inventory = Some(ranger_inventory.to_string());
+        }
+        if let Some(checkin_listen_bind) = raw["checkin_listen_bind"].as_str() {
+            self.checkin_listen_bind = Some(checkin_listen_bind.to_string());
+        }
+        self.checkins = raw["checkins"].clone();
     }
 }
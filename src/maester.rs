@@ -32,6 +32,7 @@ use crate::alert::Alert;
 use crate::raven::RavenMessage;
 
 use crate::utils::AsyncRes;
+use serde_json::Value;
 
 struct MaesterSession<'a> {
     id: u64,
@@ -61,6 +62,18 @@ impl<'a> Handler for MaesterSession<'a> {
                     RavenMessage::LoadSnapshot => {
                         self.watcher.load_snapshot_from_dispatcher();
                     },
+                    RavenMessage::ListRangers => {
+                        let data = self.watcher.list_rangers();
+                        let _ = self.out.send(RavenMessage::Rangers { data: &data }.to_json());
+                    },
+                    RavenMessage::RangerCommand { path, command } => {
+                        // Acknowledgement from the ranger is broadcasted later
+                        let data = match self.watcher.send_ranger_command(&path, command) {
+                            Ok(seq) => json!({ "seq": seq, "path": path, "success": true, "message": "Command sent" }),
+                            Err(message) => json!({ "seq": 0, "path": path, "success": false, "message": message }),
+                        };
+                        let _ = self.out.send(RavenMessage::RangerCommandSent { data: &data }.to_json());
+                    },
                     _ => {}
                 }
             },
@@ -141,6 +154,10 @@ impl Maester {
         self.broadcast(&RavenMessage::NewEvent { data: event }.to_json());
     }
 
+    pub fn on_ranger_ack(&self, ack: &Value) {
+        self.broadcast(&RavenMessage::RangerAck { data: ack }.to_json());
+    }
+
     fn broadcast(&self, data: &str) {
         let state = self.state.read().unwrap();
         for sender in state.sessions.values() {
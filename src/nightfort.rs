@@ -21,9 +21,9 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use std::sync::{Arc, Weak};
+use std::sync::{Arc, Weak, Mutex};
 use crate::watcher::*;
-use crate::utils::{self, AsyncRes};
+use crate::utils::{self, AsyncRes, JsonParser};
 use tokio::{
     self,
     net::{TcpListener, TcpStream},
@@ -32,25 +32,229 @@ use tokio_util::codec::Framed;
 use std::net::SocketAddr;
 use crate::node::*;
 use std::collections::HashMap;
-use crate::dracarys::{Dracarys, DracarysFramer};
-use futures::{StreamExt};
-use serde_json::{self, json};
+use crate::dracarys::{Dracarys, DracarysFramer, RangerCommand, REPORT_FLAPPING, REPORT_DEPENDENCY_FAILED, REPORT_REPLAYED};
+use tokio::sync::mpsc;
+use futures::{StreamExt, SinkExt};
+use serde_json::{self, json, Value};
+use crate::inventory::Inventory;
+use std::sync::RwLock;
+use std::fs;
+
+// Leaf nodes claimed by connected rangers, leaf path -> (ranger address, target key)
+type Claims = Arc<Mutex<HashMap<String, (SocketAddr, String)>>>;
+
+// Targets inventory for managed rangers
+type SharedInventory = Arc<RwLock<Option<Inventory>>>;
+
+// Connected ranger with its identity
+pub struct RangerInfo {
+    hello: Value,
+    commander: mpsc::UnboundedSender<Dracarys>,
+    // Last pushed config
+    config: Option<String>,
+    connected: u64,
+    last_seen: u64,
+    uptime: u64,
+    frames: u64,
+    // Registered targets, id -> leaf path
+    targets: HashMap<u16, String>,
+}
+
+impl RangerInfo {
+    fn dump(&self, addr: &SocketAddr) -> Value {
+        let mut targets: Vec<Value> = self.targets.iter().map(|(id, leaf)| json!({ "id": id, "leaf": leaf })).collect();
+        targets.sort_by(|a, b| a["leaf"].as_str().cmp(&b["leaf"].as_str()));
+        json!({
+            "addr": addr.to_string(),
+            "hostname": self.hello["hostname"],
+            "labels": self.hello["labels"],
+            "version": self.hello["version"],
+            "managed": self.hello["managed"],
+            "connected": self.connected,
+            "last_seen": self.last_seen,
+            "uptime": self.uptime,
+            "frames": self.frames,
+            "targets": targets,
+        })
+    }
+}
+
+// Registry of connected rangers, and rangers by leaf node id to route commands from maester
+pub struct Couriers {
+    rangers: HashMap<SocketAddr, RangerInfo>,
+    leaves: HashMap<u64, (SocketAddr, u16, mpsc::UnboundedSender<Dracarys>)>,
+    // Leaf path and send time of commands waiting for ack
+    pending: HashMap<u32, (String, u64)>,
+    seq: u32,
+}
+
+impl Couriers {
+    pub fn new() -> Arc<Mutex<Couriers>> {
+        Arc::new(Mutex::new(Couriers {
+            rangers: HashMap::new(),
+            leaves: HashMap::new(),
+            pending: HashMap::new(),
+            seq: 0,
+        }))
+    }
+
+    fn connect(&mut self, addr: SocketAddr, commander: mpsc::UnboundedSender<Dracarys>) {
+        let now = utils::now();
+        self.rangers.insert(addr, RangerInfo {
+            hello: json!({}),
+            commander,
+            config: None,
+            connected: now,
+            last_seen: now,
+            uptime: 0,
+            frames: 0,
+            targets: HashMap::new(),
+        });
+    }
+
+    fn touch(&mut self, addr: &SocketAddr) {
+        if let Some(ranger) = self.rangers.get_mut(addr) {
+            ranger.last_seen = utils::now();
+            ranger.frames += 1;
+        }
+    }
+
+    fn register(&mut self, node: u64, addr: SocketAddr, id: u16, leaf: &String, commander: mpsc::UnboundedSender<Dracarys>) {
+        self.leaves.insert(node, (addr, id, commander));
+        if let Some(ranger) = self.rangers.get_mut(&addr) {
+            ranger.targets.insert(id, leaf.clone());
+        }
+    }
+
+    fn deregister(&mut self, node: Option<u64>, addr: SocketAddr, id: u16) {
+        if let Some(node) = node {
+            if self.leaves.get(&node).map(|c| c.0 == addr).unwrap_or(false) {
+                self.leaves.remove(&node);
+            }
+        }
+        if let Some(ranger) = self.rangers.get_mut(&addr) {
+            ranger.targets.remove(&id);
+        }
+    }
+
+    pub fn dump(&self) -> Value {
+        let mut rangers: Vec<Value> = self.rangers.iter().map(|(addr, ranger)| ranger.dump(addr)).collect();
+        rangers.sort_by(|a, b| a["addr"].as_str().cmp(&b["addr"].as_str()));
+        Value::Array(rangers)
+    }
+
+    pub fn send_command(&mut self, node: u64, path: &String, command: RangerCommand) -> Result<u32, String> {
+        let (_, id, commander) = match self.leaves.get(&node) {
+            Some(courier) => courier,
+            None => return Err(format!("No ranger connected for {}", path)),
+        };
+        self.seq = self.seq.wrapping_add(1);
+        let seq = self.seq;
+        if commander.send(Dracarys::Command { id: *id, seq, command }).is_err() {
+            return Err(format!("Ranger for {} is disconnected", path));
+        }
+        // Forget commands never acknowledged
+        let now = utils::now();
+        self.pending.retain(|_, pending| pending.1 + 300 > now);
+        self.pending.insert(seq, (path.clone(), now));
+        Ok(seq)
+    }
+
+    fn take_pending(&mut self, seq: u32) -> Option<String> {
+        self.pending.remove(&seq).map(|pending| pending.0)
+    }
+
+    // Push targets from inventory to managed rangers, when they changed
+    fn push_configs(&mut self, inventory: &Inventory) {
+        for (addr, ranger) in self.rangers.iter_mut() {
+            if !ranger.hello.get_bool("managed", false) { continue; }
+            let config = json!({ "targets": inventory.targets(&ranger.hello) }).to_string();
+            if ranger.config.as_ref() == Some(&config) { continue; }
+            info!("Pushing targets to ranger {}", addr);
+            let _ = ranger.commander.send(Dracarys::Config { data: config.clone() });
+            ranger.config = Some(config);
+        }
+    }
+}
 
 struct ColdHands {
     hands: HashMap<u16, Weak<Node>>,
+    keys: HashMap<u16, String>,
+    leaves: HashMap<u16, String>,
     watcher: Weak<Watcher>,
+    addr: SocketAddr,
+    claims: Claims,
+    commander: mpsc::UnboundedSender<Dracarys>,
+    inventory: SharedInventory,
 }
 
 impl ColdHands {
-    pub fn new(watcher: Weak<Watcher>) -> ColdHands {
+    pub fn new(watcher: Weak<Watcher>, addr: SocketAddr, claims: Claims, commander: mpsc::UnboundedSender<Dracarys>, inventory: SharedInventory) -> ColdHands {
         ColdHands {
             hands: HashMap::new(),
+            keys: HashMap::new(),
+            leaves: HashMap::new(),
             watcher: watcher,
+            addr,
+            claims,
+            commander,
+            inventory,
+        }
+    }
+
+    // Check the registration against targets of this ranger and leaves claimed by other rangers
+    fn check_registration(&self, id: u16, key: &String, leaf: &String) -> Result<(), String> {
+        if let Some(registered) = self.keys.get(&id) {
+            if registered != key {
+                return Err(format!("Target id {} is already registered for target {}", id, registered));
+            }
+        }
+        if !key.is_empty() {
+            for (other, registered) in self.keys.iter() {
+                if *other != id && registered == key {
+                    return Err(format!("Target {} is already registered with id {}", key, other));
+                }
+            }
+        }
+        if let Some(&(addr, ref registered)) = self.claims.lock().unwrap().get(leaf) {
+            // A reconnected ranger can take over its own leaves, but no other target can claim them
+            if registered != key || (addr != self.addr && addr.ip() != self.addr.ip()) {
+                return Err(format!("Leaf {} is already watched by ranger {}", leaf, addr));
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&mut self, id: u16) {
+        let node_id = self.hands.remove(&id).and_then(|node| node.upgrade()).map(|node| node.read().unwrap().id);
+        if let Some(watcher) = self.watcher.upgrade() {
+            watcher.couriers.lock().unwrap().deregister(node_id, self.addr, id);
+        }
+        self.keys.remove(&id);
+        if let Some(leaf) = self.leaves.remove(&id) {
+            let mut claims = self.claims.lock().unwrap();
+            if claims.get(&leaf).map(|c| c.0 == self.addr).unwrap_or(false) {
+                claims.remove(&leaf);
+            }
+        }
+    }
+
+    pub fn release_all(&mut self) {
+        let ids: Vec<u16> = self.hands.keys().cloned().collect();
+        for id in ids {
+            self.release(id);
+        }
+        if let Some(watcher) = self.watcher.upgrade() {
+            watcher.couriers.lock().unwrap().rangers.remove(&self.addr);
         }
     }
-    pub async fn process(&mut self, msg: Dracarys) -> AsyncRes {
+
+    pub async fn process(&mut self, msg: Dracarys, replies: &mut Vec<Dracarys>) -> AsyncRes {
+        if let Some(watcher) = self.watcher.upgrade() {
+            watcher.couriers.lock().unwrap().touch(&self.addr);
+        }
         match msg {
-            Dracarys::Target { id, ref paths, ref name, ref extra } => {
+            Dracarys::Target { id, ref paths, ref name, ref extra, ref key } => {
                 let watcher = self.watcher.upgrade().unwrap();
                 // Check if any parent exist first
                 if watcher.locate_node_with_paths(paths).is_none() {
@@ -63,6 +267,11 @@ impl ColdHands {
                 for path in paths.iter() {
                     lock_paths.push(path.clone() + "." + name);
                 }
+                if let Err(message) = self.check_registration(id, key, &lock_paths[0]) {
+                    error!("Rejected target registration from {}: {}", self.addr, message);
+                    replies.push(Dracarys::Error { id, message });
+                    return Ok(());
+                }
                 let mut leaf: Option<Weak<Node>> = None;
                 // Try locate the node with paths first to void locking
                 leaf = watcher.locate_node_with_paths(&lock_paths);
@@ -88,17 +297,45 @@ impl ColdHands {
                     }
                 }
                 if let Some(ranger) = leaf {
+                    if let Some(node) = ranger.upgrade() {
+                        // Expected report interval can change with the registration, like for jobs
+                        if let Ok(raw) = serde_json::from_str::<Value>(extra) {
+                            if let Some(threshold) = raw["health_report_threshold"].as_u64() {
+                                node.write().unwrap().health_report_threshold = threshold.min(std::u32::MAX as u64) as u32;
+                            }
+                        }
+                        let node_id = node.read().unwrap().id;
+                        watcher.couriers.lock().unwrap().register(node_id, self.addr, id, &lock_paths[0], self.commander.clone());
+                    }
                     self.hands.insert(id, ranger);
+                    self.keys.insert(id, key.clone());
+                    self.leaves.insert(id, lock_paths[0].clone());
+                    self.claims.lock().unwrap().insert(lock_paths[0].clone(), (self.addr, key.clone()));
                     info!("Successfully allocated/found the ranger for {:?}", msg);
                 } else {
                     warn!("Failed to Find or allocate the ranger");
                 }
             },
-            Dracarys::Report { id, health_status } => {
+            Dracarys::Report { id, health_status, timestamp, flags } => {
                 if let Some(node) = self.hands.get(&id) {
                     if let Some(state) = node.upgrade() {
                         let mut state = state.write().unwrap();
+                        // Report replayed after reconnect only goes into the metric history
+                        if flags & REPORT_REPLAYED != 0 {
+                            let watcher = self.watcher.upgrade().unwrap();
+                            for path in state.get_paths().iter() {
+                                watcher.dispatcher.send_metric((path, health_status, timestamp).into());
+                            }
+                            info!("Recorded late health status for ranger with id {} at {}", id, timestamp);
+                            return Ok(());
+                        }
                         state.health_status = health_status;
+                        for (flag, name) in [(REPORT_FLAPPING, "flapping"), (REPORT_DEPENDENCY_FAILED, "skipped for failed dependency")].iter() {
+                            if (flags ^ state.health_flags) & flag != 0 {
+                                info!("Ranger with id {} is {} {}", id, if flags & flag != 0 { "now" } else { "no longer" }, name);
+                            }
+                        }
+                        state.health_flags = flags;
                         state.health_last_report = utils::now();
                         info!("Successfully updated health status for ranger with id {}", id);
                     } else {
@@ -110,6 +347,12 @@ impl ColdHands {
             },
             Dracarys::Message { id, ref data } => {
                 info!("Message from id: {} ranger: {}", id, data);
+                if let Some(node) = self.hands.get(&id) {
+                    if let Some(state) = node.upgrade() {
+                        let mut state = state.write().unwrap();
+                        state.health_last_message = data.clone();
+                    }
+                }
             },
             Dracarys::Metric { id, relative, ref metrics } => {
                 let watcher = self.watcher.upgrade().unwrap();
@@ -131,7 +374,60 @@ impl ColdHands {
                         watcher.dispatcher.send_metric((&m.0, &m.1, &m.2).into());
                     }
                 }
-            }
+            },
+            Dracarys::Retire { id } => {
+                // Target removed from the ranger config, detach the leaf node
+                if let Some(node) = self.hands.get(&id).cloned() {
+                    self.release(id);
+                    let watcher = self.watcher.upgrade().unwrap();
+                    watcher.retire_ranger(&node);
+                    info!("Retired the ranger with id {}", id);
+                } else {
+                    warn!("Ranger tells false tales: {:?}", msg);
+                }
+            },
+            Dracarys::Error { id, ref message } => {
+                error!("Ranger {} reported error for id {}: {}", self.addr, id, message);
+            },
+            Dracarys::Ack { id, seq, success, ref message } => {
+                info!("Ranger {} acknowledged command {} for id {}: {}", self.addr, seq, id, message);
+                let watcher = self.watcher.upgrade().unwrap();
+                let path = watcher.couriers.lock().unwrap().take_pending(seq).unwrap_or_default();
+                watcher.maester.on_ranger_ack(&json!({
+                    "seq": seq,
+                    "path": path,
+                    "success": success,
+                    "message": message,
+                }));
+            },
+            Dracarys::Hello { ref data } => {
+                let hello: Value = serde_json::from_str(data).unwrap_or(json!({}));
+                info!("Ranger {} introduced itself as {}", self.addr, hello);
+                let watcher = self.watcher.upgrade().unwrap();
+                let mut couriers = watcher.couriers.lock().unwrap();
+                // Connections are listed once introduced, so that bare connection probes of
+                // rangers checking for failback leave no trace
+                if !couriers.rangers.contains_key(&self.addr) {
+                    couriers.connect(self.addr, self.commander.clone());
+                }
+                if let Some(ranger) = couriers.rangers.get_mut(&self.addr) {
+                    ranger.hello = hello;
+                    ranger.config = None;
+                }
+                if let Some(ref inventory) = *self.inventory.read().unwrap() {
+                    couriers.push_configs(inventory);
+                }
+            },
+            Dracarys::Heartbeat { uptime } => {
+                let watcher = self.watcher.upgrade().unwrap();
+                let mut couriers = watcher.couriers.lock().unwrap();
+                if let Some(ranger) = couriers.rangers.get_mut(&self.addr) {
+                    ranger.uptime = uptime;
+                }
+            },
+            Dracarys::Command { .. } | Dracarys::Config { .. } => {
+                warn!("Ranger tells false tales: {:?}", msg);
+            },
         }
         Ok(())
     }
@@ -140,6 +436,8 @@ impl ColdHands {
 pub struct Nightfort {
     watcher: Weak<Watcher>,
     listen_bind: String,
+    claims: Claims,
+    inventory: SharedInventory,
 }
 
 impl Nightfort {
@@ -149,6 +447,8 @@ impl Nightfort {
         Nightfort {
             watcher,
             listen_bind,
+            claims: Arc::new(Mutex::new(HashMap::new())),
+            inventory: Arc::new(RwLock::new(None)),
         }
     }
 
@@ -160,6 +460,8 @@ impl Nightfort {
             self.listen_bind = landing.nightfort_listen_bind.clone();
         }
 
+        self.watch_inventory();
+
         // listen and bind
         // let addr = self.listen_bind.to_socket_addrs().unwrap().next().unwrap();
         let addr: SocketAddr  = self.listen_bind.parse().unwrap();
@@ -171,10 +473,12 @@ impl Nightfort {
                 Ok((stream, addr)) => {
                     info!("debug2");
                     let watcher = self.watcher.clone();
+                    let claims = self.claims.clone();
+                    let inventory = self.inventory.clone();
                     info!("debug3");
                     tokio::spawn(async move {
                         info!("debug4");
-                        if let Err(e) = Nightfort::process(watcher, stream, addr).await {
+                        if let Err(e) = Nightfort::process(watcher, claims, inventory, stream, addr).await {
                             error!("Error on this ranger: {}, error: {:?}", addr, e);
                         }
                     });
@@ -186,28 +490,108 @@ impl Nightfort {
         }
     }
 
-    pub async fn process(watcher: Weak<Watcher>, stream: TcpStream, addr: SocketAddr) -> AsyncRes {
+    // Load the ranger inventory and push changes to connected rangers
+    fn watch_inventory(&self) {
+        let path = {
+            let watcher = self.watcher.upgrade().unwrap();
+            let landing = watcher.landing.read().unwrap();
+            match landing.ranger_inventory {
+                Some(ref path) => path.clone(),
+                None => return,
+            }
+        };
+        let watcher = self.watcher.clone();
+        let inventory = self.inventory.clone();
+        tokio::spawn(async move {
+            let mut last_modified = None;
+            loop {
+                let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if modified.is_some() && modified != last_modified {
+                    last_modified = modified;
+                    if let Some(new_inventory) = Inventory::load(&path) {
+                        info!("Loaded ranger inventory from {}", path);
+                        let watcher = match watcher.upgrade() {
+                            Some(watcher) => watcher,
+                            None => break,
+                        };
+                        watcher.couriers.lock().unwrap().push_configs(&new_inventory);
+                        *inventory.write().unwrap() = Some(new_inventory);
+                    }
+                }
+                sleep!(5000);
+            }
+        });
+    }
+
+    pub async fn process(watcher: Weak<Watcher>, claims: Claims, inventory: SharedInventory, stream: TcpStream, addr: SocketAddr) -> AsyncRes {
         info!("debug5");
-        let mut handler = ColdHands::new(watcher);
+        let (commander, mut commands) = mpsc::unbounded_channel();
+        let mut handler = ColdHands::new(watcher, addr, claims, commander, inventory);
         info!("debug6");
         let mut stream = Framed::new(stream, DracarysFramer::new());
         info!("New Ranger get connected from: {}", addr);
 
-        loop {
-            match stream.next().await {
+        let mut replies = Vec::new();
+        let res = loop {
+            let msg = tokio::select! {
+                msg = stream.next() => msg,
+                Some(command) = commands.recv() => {
+                    // Commands from maester to the ranger
+                    if let Err(e) = stream.send(command).await {
+                        error!("Failed to send command to ranger {}, error: {:?}", addr, e);
+                    }
+                    continue;
+                },
+            };
+            match msg {
                 Some(Ok(msg)) => {
                     // info!("Nightfort rx: {:?}", msg);
-                    handler.process(msg).await?;
+                    if let Err(e) = handler.process(msg, &mut replies).await { break Err(e); }
+                    for reply in replies.drain(..) {
+                        if let Err(e) = stream.send(reply).await {
+                            error!("Failed to reply to ranger {}, error: {:?}", addr, e);
+                        }
+                    }
                 },
                 Some(Err(e)) => {
                     error!("Nightfor met error: {:?}", e);
-                    return Ok(());
+                    break Ok(());
                 },
                 None => { 
                     warn!("We lost connection with this ranger from {}", addr);
-                    return Ok(());
+                    break Ok(());
                 }
             }
-        }
+        };
+        // Leaves can be claimed by other rangers now
+        handler.release_all();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::landing::Landing;
+    use crate::maester::Maester;
+
+    #[tokio::test]
+    async fn test_slow_report_is_live() {
+        let maester = Maester::new();
+        let watcher = Arc::new(Watcher::new(Landing::new(), &maester));
+        let (commander, _commands) = mpsc::unbounded_channel();
+        let claims = Arc::new(Mutex::new(HashMap::new()));
+        let mut hands = ColdHands::new(Arc::downgrade(&watcher), "127.0.0.1:6000".parse().unwrap(), claims, commander, Arc::new(RwLock::new(None)));
+        let mut replies = Vec::new();
+        let node: Arc<Node> = Arc::new(RwLock::new(NodeProto::new()));
+        hands.hands.insert(1, Arc::downgrade(&node));
+
+        // Check took longer than the report threshold, the report is still live
+        let started = utils::now() - 120;
+        hands.process(Dracarys::Report { id: 1, health_status: 80, timestamp: started, flags: 0 }, &mut replies).await.unwrap();
+        assert_eq!(node.read().unwrap().health_status, 80);
+        // Replayed reports only go into the history
+        hands.process(Dracarys::Report { id: 1, health_status: 10, timestamp: started, flags: REPORT_REPLAYED }, &mut replies).await.unwrap();
+        assert_eq!(node.read().unwrap().health_status, 80);
     }
 }
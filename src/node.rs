@@ -31,6 +31,7 @@ use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use std::time::{Instant, Duration};
 use crate::eval::*;
+use crate::dracarys::REPORT_DEPENDENCY_FAILED;
 
 // use log::{warn, info};
 
@@ -237,6 +238,8 @@ pub struct NodeProto {
     pub alert_description: String,
 
     pub health_status: u8,
+    // Report flags from the ranger, like flapping
+    pub health_flags: u8,
     pub health_check_eval: Option<String>,
     pub health_check_eval_override: Option<String>,
     pub health_check_eval_change: u64,
@@ -249,9 +252,10 @@ pub struct NodeProto {
     pub health_last_check: u64,
     pub health_last_report: u64,
     pub health_last_change: u64,
+    pub health_last_message: String,
 
     pub health_alert_threshold: u8,
-    pub health_report_threshold: u16,
+    pub health_report_threshold: u32,
 
     pub app_meta_map: AppMetaMap,
 }
@@ -274,6 +278,7 @@ impl NodeProto {
             alert_enabled: true,
             alert_description: String::new(),
             health_status: 255,
+            health_flags: 0,
             health_check_eval: None,
             health_check_eval_override: None,
             health_check_eval_change: 0,
@@ -286,6 +291,7 @@ impl NodeProto {
             health_last_check: 0,
             health_last_report: utils::now(),
             health_last_change: 0,
+            health_last_message: String::new(),
             health_alert_threshold: 1,
             health_report_threshold: 30,
             app_meta_map: HashMap::new(),
@@ -329,9 +335,11 @@ impl NodeProto {
             "alert_enabled": self.alert_enabled,
             "alert_description": self.alert_description,
             "health_status": self.health_status,
+            "health_flags": self.health_flags,
             "health_check_eval": self.health_check_eval,
             "health_check_type": self.health_check_type.to_string(),
             "health_event_enabled": self.health_event_enabled,
+            "health_last_message": self.health_last_message,
             "health_alert_threshold": self.health_alert_threshold,
             "health_report_threshold": self.health_report_threshold
         })
@@ -348,7 +356,7 @@ impl NodeProto {
         self.alert_description = raw.get_str("alert_description", "");
         self.health_event_enabled = raw.get_bool("health_event_enabled", true);
         self.health_alert_threshold = raw.get_u64("health_alert_threshold", 1) as u8;
-        self.health_report_threshold = raw.get_u64("health_report_threshold", 1) as u16;
+        self.health_report_threshold = raw.get_u64("health_report_threshold", 1) as u32;
         if let Some(script) = raw["health_check_eval"].as_str() {
             self.health_check_eval_override = Some(script.to_string());
         }
@@ -387,6 +395,8 @@ impl NodeProto {
             for node in self.children.iter() {
                 if let Some(child_node) = node.upgrade() {
                     let child = child_node.read().unwrap();
+                    // Leaves skipped for a failed dependency do not count
+                    if child.health_flags & REPORT_DEPENDENCY_FAILED != 0 { continue; }
                     count += 1;
                     amount += child.health_status as u32;
                 }
@@ -442,6 +452,7 @@ pub trait StoreOps {
     fn update_index(&self, name: &String, index: u64);
     fn get_weak_node(&self, path: &String) -> Option<Weak<Node>>;
     fn get_node(&self, id: &u64) -> Option<Arc<Node>>;
+    fn remove_node(&self, id: &u64);
     fn deserialize_node(&self, raw: &Value) -> Arc<Node>;
 }
 
@@ -467,7 +478,7 @@ impl StoreOps for Arc<Store> {
             state.alert_description = raw.get_str("alert_description", "");
             state.health_event_enabled = raw.get_bool("health_event_enabled", true);
             state.health_alert_threshold = raw.get_u64("health_alert_threshold", 1) as u8;
-            state.health_report_threshold = raw.get_u64("health_report_threshold", 30) as u16;
+            state.health_report_threshold = raw.get_u64("health_report_threshold", 30) as u32;
 
             if let Some(script) = raw["health_check_eval"].as_str() {
                 state.health_check_eval_override = Some(script.to_string());
@@ -510,6 +521,12 @@ impl StoreOps for Arc<Store> {
         }
     }
 
+    fn remove_node(&self, id: &u64) {
+        let mut state = self.write().unwrap();
+        state.store.remove(id);
+        state.index.retain(|_, index| index != id);
+    }
+
     fn get_weak_node(&self, path: &String) -> Option<Weak<Node>> {
         let state = self.read().unwrap();
         if let Some(id) = state.index.get(path) {
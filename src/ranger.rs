@@ -21,21 +21,71 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use std::collections::{VecDeque, HashMap};
+use std::collections::{VecDeque, HashMap, HashSet};
 use serde_json::Value;
 use crate::utils::{JsonParser, AsyncRes};
 use crate::knight::*;
-use crate::dracarys::{Dracarys, DracarysFramer};
-use tokio::sync::mpsc;
+use crate::dracarys::{Dracarys, DracarysFramer, RangerCommand, REPORT_FLAPPING, REPORT_DEPENDENCY_FAILED, REPORT_REPLAYED};
+use crate::spool::{self, Spool};
+use crate::cron::Schedule;
+use crate::statsd::Statsd;
+use rand::Rng;
+use tokio::sync::{mpsc, Notify, Semaphore};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use crate::utils;
 use std::time::{Duration, Instant};
 use tokio::process::Command;
+use tokio::time;
+use tokio::task;
+use std::process::{Command as StdCommand, Stdio};
+use std::os::unix::process::CommandExt;
+use std::fs;
+use std::fmt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use tokio::net::{UnixListener, UdpSocket};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::signal::unix::{signal, SignalKind};
+use futures::future;
+use regex::Regex;
+use crate::watch::tcp::TcpWatch;
+use crate::watch::http::HttpWatch;
+use crate::watch::json;
+use crate::watch::nagios::NagiosWatch;
+use crate::watch::host::HostWatch;
+use crate::watch::process::ProcessWatch;
+use crate::watch::log::LogWatch;
+use crate::watch::prometheus::PrometheusWatch;
+use crate::watch::passive::{self, PassiveWatch, Push};
+use crate::watch::expect::ExpectWatch;
 // Sample configuration
 //
-// nightfort: 127.0.0.1:6000
+// nightfort: 127.0.0.1:6000  # or a list of addresses
+// nightfort_mode: failover  # failover: use the first available address, fanout: send to all
+// failback_interval: 30     # seconds between checks whether a preferred address is back
+// reload_interval: 5     # seconds between checks of the config file for changes
+// hostname: web-1        # identity of the ranger, defaults to the system hostname
+// labels:
+//   role: web
+// managed: true          # accept targets pushed from nightfort, by default when no local targets
+// heartbeat_interval: 10 # seconds between heartbeats to nightfort
+// splay: 0              # max random delay in seconds of the first check, or of each cron run
+// max_concurrent_checks: 0  # checks running at the same time, others wait in a queue, 0 for no limit
+// self_metrics_path: .rangers.web-1  # path of the ranger metrics like check queue wait time
+// passive:               # listeners for pushes of the passive targets
+//   socket: /run/nightswatch/ranger.sock
+//   socket_mode: "660"
+//   udp: 127.0.0.1:6010
+// statsd:                # embedded statsd listener, see statsd.rs
+//   bind: 127.0.0.1:8125
+//   path: .apps.web-1
+// spool:                 # keep reports and metrics on disk while disconnected
+//   path: /var/lib/nightswatch/spool
+//   max_bytes: 10485760
+//   max_age: 3600
 // targets:
-//  - watch:
+//  - id: pod1-service   # stable identity of the target, defaults to name and paths
+//    watch:
 //      program:
 //      args: []
 //      type:
@@ -45,6 +95,17 @@ use tokio::process::Command;
 //      - .app2.service2
 //    name: pod1
 //    interval: 10
+//    cron: "0 3 * * *"  # run on a cron schedule in local time instead of the interval
+//    splay: 0           # overrides the splay of the ranger
+//    timeout: 10
+//    timeout_health: 0
+//    fail_health: 1     # health at or below it counts as a failure
+//    down_after: 1      # consecutive failures before reporting down
+//    up_after: 1        # consecutive successes before reporting up
+//    flap_window: 20    # number of recent checks to detect flapping
+//    flap_threshold: 5  # state changes in the window to mark the target flapping, 0 to disable
+//    depends_on: [db]   # names or ids of targets, the check is skipped while any of them is down
+//    dependency_health: 255  # health reported when the check is skipped, left out of the parent health
 //    extra:
 //      display_name: ""
 //      description: ""
@@ -60,6 +121,22 @@ pub struct State {
     last_check: u64,
     health_status: u8,
     health_history: VecDeque<u8>,
+    // Health last reported to nightfort and consecutive checks against it
+    reported: Option<u8>,
+    failures: u32,
+    successes: u32,
+    flapping: bool,
+    // Check skipped for a failed dependency
+    skipped: bool,
+}
+
+// Runtime changes of the target schedule from nightfort commands
+#[derive(Default)]
+pub struct Control {
+    paused: bool,
+    run_now: bool,
+    // Interval override and the time it expires
+    interval: Option<(u64, u64)>,
 }
 
 pub enum TargetCheckType {
@@ -67,10 +144,21 @@ pub enum TargetCheckType {
     WatchOutput,
     WatchExitAndMetric,
     WatchMetric,
+    WatchJson,
+    WatchNagios(NagiosWatch),
+    WatchTcp(TcpWatch),
+    WatchHttp(HttpWatch),
+    WatchHost(Arc<HostWatch>),
+    WatchProcess(ProcessWatch),
+    WatchLog(LogWatch),
+    WatchPrometheus(PrometheusWatch),
+    WatchPassive(PassiveWatch),
+    WatchExpect(ExpectWatch),
 }
 
 pub struct Target {
     id: u16,
+    key: String,
     check_prog: String,
     check_args: Vec<String>,
     check_type: TargetCheckType,
@@ -79,25 +167,179 @@ pub struct Target {
     paths: Vec<String>,
     name: String,
     interval: u64,
+    cron: Option<Schedule>,
+    // Random delay picked once for the target, added to the first check and to every cron run
+    jitter: u64,
+    timeout: Duration,
+    timeout_health: u8,
+    fail_health: u8,
+    down_after: u32,
+    up_after: u32,
+    flap_window: usize,
+    flap_threshold: usize,
+    depends_on: Vec<String>,
+    dependency_health: u8,
     extra: Value,
+    // Raw config of the target, to detect changes when reloading
+    raw: Value,
+    retired: AtomicBool,
+    control: Mutex<Control>,
+    // Wakes up the target from sleeping for commands and retirement
+    wake: Notify,
 
     state: Arc<Mutex<State>>,
 }
 
 impl Target {
-    pub async fn check_health(&self, health_status: &mut u8, metrics: &mut Vec<(String, String)>) -> AsyncRes {
+    // Interval set by command with the time it expires
+    fn current_interval(&self) -> Option<(u64, u64)> {
+        let control = self.control.lock().unwrap();
+        match control.interval {
+            Some((interval, until)) if until > utils::now() => Some((interval, until)),
+            _ => None,
+        }
+    }
+
+    // Time of the next check, the interval set by command takes precedence over the cron schedule,
+    // none when the cron schedule has no more runs
+    fn next_check(&self, last_check: u64, started: u64) -> Option<u64> {
+        let interval = self.current_interval();
+        match self.cron {
+            Some(ref cron) if interval.is_none() => {
+                let base = if last_check == 0 { started } else { last_check.saturating_sub(self.jitter) };
+                cron.next_after(base).map(|next| next + self.jitter)
+            },
+            _ if last_check == 0 => Some(started + self.jitter),
+            // Wake up when the temporary interval expires to fall back to the schedule
+            _ => Some(match interval {
+                Some((interval, until)) => (last_check + interval).min(until),
+                None => last_check + self.interval,
+            }),
+        }
+    }
+
+    fn command(&self, command: &RangerCommand) -> String {
+        let mut control = self.control.lock().unwrap();
+        let message = match *command {
+            RangerCommand::RunNow => {
+                control.run_now = true;
+                "Check scheduled to run now".to_string()
+            },
+            RangerCommand::Pause => {
+                control.paused = true;
+                "Checks paused".to_string()
+            },
+            RangerCommand::Resume => {
+                control.paused = false;
+                "Checks resumed".to_string()
+            },
+            RangerCommand::SetInterval { interval, duration } => {
+                if interval == 0 {
+                    control.interval = None;
+                    format!("Interval restored to {} seconds", self.interval)
+                } else {
+                    let until = if duration > 0 { utils::now() + duration as u64 } else { std::u64::MAX };
+                    control.interval = Some((interval as u64, until));
+                    format!("Interval set to {} seconds", interval)
+                }
+            },
+        };
+        self.wake.notify();
+        message
+    }
+
+    // Apply the checked health to the state, returns the health to report with the report flags
+    fn settle(&self, state: &mut State, health_status: u8, messages: &mut Vec<String>) -> (u8, u8) {
+        let failed = health_status <= self.fail_health;
+        state.health_status = health_status;
+        state.health_history.push_back(health_status);
+        if state.health_history.len() > 50 {
+            state.health_history.pop_front();
+        }
+        if failed {
+            state.failures += 1;
+            state.successes = 0;
+        } else {
+            state.successes += 1;
+            state.failures = 0;
+        }
+
+        // Hold the last reported health until enough consecutive checks disagree with it
+        let reported = match state.reported {
+            Some(reported) if reported > self.fail_health && failed && state.failures < self.down_after => reported,
+            Some(reported) if reported <= self.fail_health && !failed && state.successes < self.up_after => reported,
+            _ => health_status,
+        };
+        state.reported = Some(reported);
+
+        // Count the changes between failure and success over the recent checks
+        let window = state.health_history.len().min(self.flap_window);
+        let changes = state.health_history.iter().skip(state.health_history.len() - window)
+            .map(|health| *health <= self.fail_health)
+            .collect::<Vec<bool>>()
+            .windows(2)
+            .filter(|pair| pair[0] != pair[1])
+            .count();
+        let flapping = self.flap_threshold > 0 && changes >= self.flap_threshold;
+        if flapping != state.flapping {
+            state.flapping = flapping;
+            let info = if flapping {
+                format!("Target {} is flapping with {} state changes in the last {} checks", self.key, changes, window)
+            } else {
+                format!("Target {} stopped flapping", self.key)
+            };
+            info!("{}", info);
+            messages.push(utils::tidings("flapping", &info));
+        }
+        (reported, if state.flapping { REPORT_FLAPPING } else { 0 })
+    }
+
+    pub async fn check_health(&self, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>, messages: &mut Vec<String>) -> AsyncRes {
         let mut success = false;
         *health_status = self.default_health;
 
+        // Native watches without forking a check program
+        match self.check_type {
+            TargetCheckType::WatchTcp(ref watch) => { return watch.check(health_status).await; },
+            TargetCheckType::WatchHttp(ref watch) => { return watch.check(health_status, metrics).await; },
+            TargetCheckType::WatchHost(ref watch) => {
+                return match time::timeout(self.timeout, watch.clone().check(health_status, metrics)).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        let info = format!("Host check timed out after {:?}", self.timeout);
+                        warn!("{}", info);
+                        *health_status = self.timeout_health;
+                        messages.push(utils::tidings("timeout", &info));
+                        Ok(())
+                    }
+                };
+            },
+            TargetCheckType::WatchProcess(ref watch) => { return watch.check(health_status, metrics, messages).await; },
+            TargetCheckType::WatchLog(ref watch) => { return watch.check(health_status, metrics, messages).await; },
+            TargetCheckType::WatchPrometheus(ref watch) if watch.url.is_some() => { return watch.check(health_status, metrics).await; },
+            TargetCheckType::WatchExpect(ref watch) => { return watch.check(health_status, metrics, messages).await; },
+            _ => {}
+        }
+
         if self.check_prog.len() < 1 { return Ok(()); }
 
-        let mut bin = Command::new(self.check_prog.clone());
-        let cmd = bin.args(&self.check_args);
+        let mut bin = StdCommand::new(self.check_prog.clone());
+        bin.args(&self.check_args);
+        // Run the check in its own process group, so that a timeout can kill everything it forked
+        unsafe {
+            bin.pre_exec(|| {
+                libc::setpgid(0, 0);
+                Ok(())
+            });
+        }
+        let mut cmd = Command::from(bin);
+        cmd.kill_on_drop(true);
 
         let mut check_metrics = false;
         let mut output_status = false;
         let mut check_output = false;
         let mut check_exit = false;
+        let mut check_json = false;
         match self.check_type {
             TargetCheckType::WatchOutput => {
                 check_output = true;
@@ -114,12 +356,35 @@ impl Target {
             TargetCheckType::WatchMetric => {
                 check_metrics = true;
                 check_output = true;
-            }
+            },
+            TargetCheckType::WatchJson => {
+                check_json = true;
+                check_output = true;
+            },
+            TargetCheckType::WatchNagios(_) => {
+                check_output = true;
+            },
+            TargetCheckType::WatchPrometheus(_) => {
+                check_output = true;
+            },
+            _ => {}
         }
 
         if check_output {
-            match cmd.output().await {
-                Ok(res) => {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        let child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                error!("Failed to run check command! {}, {:?}, error: {}", self.check_prog, self.check_args, e);
+                return Ok(());
+            }
+        };
+        let pid = child.id();
+
+        if check_output {
+            match time::timeout(self.timeout, child.wait_with_output()).await {
+                Ok(Ok(res)) => {
                     success = true;
                     // Check health status from exit code
                     if check_exit {
@@ -146,14 +411,30 @@ impl Target {
                         }
                     }
 
+                    // Collect health status, messages and metrics from json report
+                    if check_json {
+                        json::parse_report(&res.stdout, health_status, metrics, messages);
+                    }
+
+                    // Map nagios plugin exit code to health status and collect perfdata as metrics
+                    if let TargetCheckType::WatchNagios(ref watch) = self.check_type {
+                        watch.report(res.status.code(), &res.stdout, health_status, metrics, messages);
+                    }
+
+                    // Parse prometheus exposition format from output
+                    if let TargetCheckType::WatchPrometheus(ref watch) = self.check_type {
+                        watch.report(&String::from_utf8_lossy(&res.stdout), health_status, metrics);
+                    }
+
                     // Collect Metrics from stdoutput
                     if check_metrics {
                         match String::from_utf8(res.stdout) {
                             Ok(output_str) => {
+                                let now = utils::now();
                                 for line in output_str.split("\n") {
                                     let tokens: Vec<&str> = line.split(",").collect();
                                     if tokens.len() > 1 {
-                                        metrics.push((format!(".{}", tokens[0].trim()), tokens[1].trim().to_string()));
+                                        metrics.push((format!(".{}", tokens[0].trim()), tokens[1].trim().to_string(), now));
                                     }
                                 }
                             },
@@ -162,16 +443,22 @@ impl Target {
                     }
 
                 },
-                _ => {}
+                Ok(Err(_)) => {},
+                Err(_) => {
+                    self.kill_check(pid, health_status, messages);
+                    return Ok(());
+                }
             }
         } else if check_exit {
-            if let Ok(child) = cmd.spawn() {
-                match child.await {
-                    Ok(status) => {
-                        success = true;
-                        *health_status = status.code().unwrap_or(self.default_health as i32) as u8;
-                    },
-                    _ => {}
+            match time::timeout(self.timeout, child).await {
+                Ok(Ok(status)) => {
+                    success = true;
+                    *health_status = status.code().unwrap_or(self.default_health as i32) as u8;
+                },
+                Ok(Err(_)) => {},
+                Err(_) => {
+                    self.kill_check(pid, health_status, messages);
+                    return Ok(());
                 }
             }
         }
@@ -180,23 +467,119 @@ impl Target {
         }
         Ok(())
     }
+
+    async fn check_once(&self) -> Value {
+        let mut health_status = self.default_health;
+        let mut metrics = Vec::new();
+        let mut messages = Vec::new();
+        let start = Instant::now();
+        let error = match self.check_health(&mut health_status, &mut metrics, &mut messages).await {
+            Ok(_) => Value::Null,
+            Err(e) => Value::String(format!("{:?}", e)),
+        };
+        json!({
+            "key": self.key,
+            "name": self.name,
+            "paths": self.paths,
+            "health": match self.check_type {
+                TargetCheckType::WatchMetric | TargetCheckType::WatchPassive(_) => Value::Null,
+                _ => json!(health_status),
+            },
+            "fail_health": self.fail_health,
+            "elapsed": start.elapsed().as_millis() as u64,
+            "metrics": metrics.iter().map(|(path, value, _)| json!({ "path": path, "value": value })).collect::<Vec<Value>>(),
+            "messages": messages,
+            "error": error,
+        })
+    }
+
+    fn kill_check(&self, pid: u32, health_status: &mut u8, messages: &mut Vec<String>) {
+        // Kill the whole process group of the hung check
+        unsafe {
+            libc::kill(-(pid as i32), libc::SIGKILL);
+        }
+        let info = format!("Check command timed out after {:?} and was killed: {} {:?}", self.timeout, self.check_prog, self.check_args);
+        warn!("{}", info);
+        *health_status = self.timeout_health;
+        messages.push(utils::tidings("timeout", &info));
+    }
 }
 
 
 pub struct Map {
-    pub nightfort: String,
+    pub nightforts: Vec<String>,
+    pub fanout: bool,
+    pub failback_interval: u64,
+    pub reload_interval: u64,
+    pub max_concurrent_checks: usize,
     pub map: HashMap<u16, Arc<Target>>,
+    // Problems found in the config, the affected targets are skipped
+    pub problems: Vec<Problem>,
+}
+
+// Problem found in the config, with the names of the affected target if any
+pub struct Problem {
+    pub targets: Vec<String>,
+    pub info: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.info)
+    }
+}
+
+fn problem(problems: &mut Vec<Problem>, targets: &[&str], info: String) {
+    error!("{}", info);
+    problems.push(Problem {
+        targets: targets.iter().filter(|name| !name.is_empty()).map(|name| name.to_string()).collect(),
+        info,
+    });
+}
+
+// First invalid pattern of the watch, which would otherwise be dropped by the watch
+fn invalid_regex(info: &Value) -> Option<String> {
+    let watch = &info["watch"];
+    let mut patterns = vec![&watch["body_regex"], &watch["include"], &watch["exclude"]];
+    if let Some(rules) = watch["rules"].as_array() {
+        patterns.extend(rules.iter().map(|rule| &rule["pattern"]));
+    }
+    patterns.into_iter().filter_map(|pattern| pattern.as_str())
+        .find_map(|pattern| Regex::new(pattern).err().map(|e| format!("{}, {}", pattern, e)))
+}
+
+// First negative timeout of the target, the watch or its steps
+fn invalid_timeout(info: &Value) -> Option<f64> {
+    let steps = info["watch"]["steps"].as_array().map(|steps| steps.iter().collect()).unwrap_or_else(Vec::new);
+    let mut items = vec![info, &info["watch"]];
+    items.extend(steps);
+    items.iter().filter_map(|item| item["timeout"].as_f64()).find(|timeout| *timeout < 0.0)
 }
 
 impl Map {
     pub fn new(raw: &Value) -> Map {
-        let mut map = HashMap::new(); 
-        let nightfort = raw.get_str("nightfort", "127.0.0.1:6000");
+        Self::with_ids(raw, &HashMap::new())
+    }
+
+    // Targets keep the ids in known ids by key, so that the id of a target does not change when
+    // other targets with colliding ids are added or removed
+    pub fn with_ids(raw: &Value, known: &HashMap<String, u16>) -> Map {
+        let mut map = HashMap::new();
+        let mut items = Vec::new();
+        let mut keys = HashSet::new();
+        let mut problems = Vec::new();
+        let nightforts = match raw["nightfort"].as_array() {
+            Some(items) => items.iter().filter_map(|item| item.as_str().map(|s| s.to_string())).collect(),
+            None => vec![raw.get_str("nightfort", "127.0.0.1:6000")],
+        };
+        if nightforts.is_empty() {
+            problem(&mut problems, &[], "No nightfort address configured".to_string());
+        }
+        if !raw["targets"].is_null() && !raw["targets"].is_array() {
+            problem(&mut problems, &[], "Targets should be a list".to_string());
+        }
         if let Some(targets) = raw["targets"].as_array() {
             for (index, info) in targets.iter().enumerate() {
-                // target id
-                let target_id = index as u16;
-
                 // target path
                 let mut paths = Vec::new();
                 if let Some(paths_info) = info["paths"].as_array() {
@@ -207,7 +590,7 @@ impl Map {
                     }
                 }
                 if paths.len() < 1 {
-                    error!("At least one invalid parent path should be specified for leaf node");
+                    problem(&mut problems, &[info["name"].as_str().unwrap_or(""), info["id"].as_str().unwrap_or("")], format!("Target #{} {}: at least one valid parent path should be specified for leaf node", index, info["name"].as_str().unwrap_or("")));
                     continue;
                 }
 
@@ -216,18 +599,78 @@ impl Map {
                     last_check: 0,
                     health_status: 0,
                     health_history: VecDeque::new(),
+                    reported: None,
+                    failures: 0,
+                    successes: 0,
+                    flapping: false,
+                    skipped: false,
+                };
+
+                // target identity
+                let name = info.get_str("name", "new-leaf-node");
+                let key = match info["id"].as_str() {
+                    Some(id) => id.to_string(),
+                    None => format!("{}@{}", name, paths.join(",")),
+                };
+                if !keys.insert(key.clone()) {
+                    problem(&mut problems, &[&name, &key], format!("Target #{}: duplicate target {} found in config, only the first one is watched", index, key));
+                    continue;
+                }
+
+                // target schedule
+                let cron = match info["cron"].as_str() {
+                    Some(expr) => match Schedule::parse(expr) {
+                        Ok(ref cron) if cron.next_after(utils::now()).is_none() => {
+                            problem(&mut problems, &[&name, &key], format!("Target #{} {}: cron expression {} never matches", index, key, expr));
+                            continue;
+                        },
+                        Ok(cron) => Some(cron),
+                        Err(e) => {
+                            problem(&mut problems, &[&name, &key], format!("Target #{} {}: invalid cron expression, {}", index, key, e));
+                            continue;
+                        }
+                    },
+                    None => None,
                 };
+                if let Some(info) = invalid_regex(info) {
+                    problem(&mut problems, &[&name, &key], format!("Target #{} {}: invalid regex {}", index, key, info));
+                    continue;
+                }
+                if let Some(timeout) = invalid_timeout(info) {
+                    problem(&mut problems, &[&name, &key], format!("Target #{} {}: invalid timeout {}", index, key, timeout));
+                    continue;
+                }
+                let splay = info.get_u64("splay", raw.get_u64("splay", 0));
+                let jitter = if splay > 0 { rand::thread_rng().gen_range(0, splay) } else { 0 };
 
                 // target body
                 let mut target = Target {
-                    id: target_id,
+                    id: 0,
+                    key,
                     check_prog: String::new(),
                     check_type: TargetCheckType::WatchOutput,
                     relative_metric_path: info.get_bool("relative_metric_path", true),
                     check_args: Vec::new(),
-                    name: info.get_str("name", "new-leaf-node"),
+                    name,
                     interval: info.get_u64("interval", 10),
+                    cron,
+                    jitter,
+                    timeout: info.get_duration("timeout", info.get_u64("interval", 10) as f64),
+                    timeout_health: info.get_u64("timeout_health", 0) as u8,
+                    fail_health: info.get_u64("fail_health", 1) as u8,
+                    down_after: info.get_u64("down_after", 1) as u32,
+                    up_after: info.get_u64("up_after", 1) as u32,
+                    flap_window: (info.get_u64("flap_window", 20) as usize).min(50),
+                    flap_threshold: info.get_u64("flap_threshold", 5) as usize,
+                    depends_on: info["depends_on"].as_array().map(|names| {
+                        names.iter().filter_map(|name| name.as_str().map(|name| name.to_string())).collect()
+                    }).unwrap_or_default(),
+                    dependency_health: info.get_u64("dependency_health", 255) as u8,
                     extra: info["extra"].clone(),
+                    raw: info.clone(),
+                    retired: AtomicBool::new(false),
+                    control: Mutex::new(Control::default()),
+                    wake: Notify::new(),
                     paths,
                     default_health: info.get_u64("default_health", 0) as u8,
                     state: Arc::new(Mutex::new(state)),
@@ -247,6 +690,51 @@ impl Map {
                     } else if check_type == "watch_exit_and_metrics" {
                         // Check exit code as health status and collect metrics from output
                         target.check_type = TargetCheckType::WatchExitAndMetric;
+                    } else if check_type == "watch_json" {
+                        // Check health status, message and metrics from a json report in output
+                        target.check_type = TargetCheckType::WatchJson;
+                    } else if check_type == "watch_nagios" {
+                        // Check nagios plugin exit code as health status and collect perfdata as metrics
+                        target.check_type = TargetCheckType::WatchNagios(NagiosWatch::new(&info["watch"]));
+                    } else if check_type == "watch_tcp" {
+                        // Connect natively and check the optional banner
+                        target.check_type = TargetCheckType::WatchTcp(TcpWatch::new(&info["watch"]));
+                    } else if check_type == "watch_http" {
+                        // Request natively and collect the latency as metrics
+                        target.check_type = TargetCheckType::WatchHttp(HttpWatch::new(&info["watch"]));
+                    } else if check_type == "watch_host" {
+                        // Collect host metrics from /proc natively
+                        target.check_type = TargetCheckType::WatchHost(Arc::new(HostWatch::new(&info["watch"])));
+                    } else if check_type == "watch_process" {
+                        // Check process instances and collect their resource usage
+                        target.check_type = TargetCheckType::WatchProcess(ProcessWatch::new(&info["watch"]));
+                    } else if check_type == "watch_log" {
+                        // Follow a log file and apply regex rules on new lines
+                        target.check_type = TargetCheckType::WatchLog(LogWatch::new(&info["watch"]));
+                    } else if check_type == "watch_prometheus" {
+                        // Scrape prometheus metrics from url or the check output
+                        target.check_type = TargetCheckType::WatchPrometheus(PrometheusWatch::new(&info["watch"]));
+                    } else if check_type == "watch_expect" {
+                        // Walk through a scripted conversation over tcp or a unix socket
+                        target.check_type = TargetCheckType::WatchExpect(ExpectWatch::new(&info["watch"]));
+                    } else if check_type == "watch_passive" {
+                        // Status is pushed to the passive listener instead of polled
+                        target.check_type = TargetCheckType::WatchPassive(PassiveWatch::new(&info["watch"]));
+                    } else if check_type != "" && check_type != "watch_output" {
+                        problem(&mut problems, &[&target.name, &target.key], format!("Target #{} {}: unknown watch type {}", index, target.key, check_type));
+                        continue;
+                    }
+                    let native = match target.check_type {
+                        TargetCheckType::WatchTcp(_) | TargetCheckType::WatchHttp(_) | TargetCheckType::WatchHost(_) |
+                        TargetCheckType::WatchProcess(_) | TargetCheckType::WatchLog(_) | TargetCheckType::WatchPassive(_) |
+                        TargetCheckType::WatchExpect(_) => true,
+                        TargetCheckType::WatchPrometheus(ref watch) => watch.url.is_some(),
+                        _ => false,
+                    };
+                    if !native && target.check_prog.is_empty() {
+                        problem(&mut problems, &[&target.name, &target.key], format!("Target #{} {}: watch type {} requires a prog to run", index, target.key,
+                            if check_type.is_empty() { "watch_output" } else { &check_type }));
+                        continue;
                     }
                     if let Some(args) = info["watch"]["args"].as_array() {
                         for arg in args.iter() {
@@ -255,82 +743,315 @@ impl Map {
                             }
                         }
                     }
+                } else {
+                    problem(&mut problems, &[&target.name, &target.key], format!("Target #{} {}: no watch program or native watch type", index, target.key));
+                    continue;
                 }
 
-                // insert target into map
-                map.insert(target_id, Arc::new(target));
+                items.push(target);
+            }
+        }
+
+        for target in items.iter() {
+            for name in target.depends_on.iter() {
+                if !items.iter().any(|other| &other.name == name || &other.key == name) {
+                    problem(&mut problems, &[&target.name, &target.key], format!("Target {}: unknown dependency {}", target.key, name));
+                }
+            }
+        }
+
+        // Derive target id from the key, so that it does not depend on the target order
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut pending = Vec::new();
+        for mut target in items.into_iter() {
+            match known.get(&target.key) {
+                Some(&target_id) if !map.contains_key(&target_id) => {
+                    target.id = target_id;
+                    map.insert(target_id, Arc::new(target));
+                },
+                _ => pending.push(target),
+            }
+        }
+        for mut target in pending.into_iter() {
+            let mut target_id = utils::hash_u16(&target.key);
+            while map.contains_key(&target_id) {
+                target_id = target_id.wrapping_add(1);
             }
+            target.id = target_id;
+            map.insert(target_id, Arc::new(target));
         }
         Map {
-            nightfort,
+            nightforts,
+            fanout: raw.get_str("nightfort_mode", "failover") == "fanout",
+            failback_interval: raw.get_u64("failback_interval", 30),
+            reload_interval: raw.get_u64("reload_interval", 5),
+            max_concurrent_checks: raw.get_u64("max_concurrent_checks", 0) as usize,
             map,
+            problems,
         }
     }
+
+    // Keep only the targets with the name or id, and their problems
+    pub fn select(&mut self, name: &str) {
+        self.map.retain(|_, target| target.name == name || target.key == name);
+        self.problems.retain(|problem| problem.targets.iter().any(|target| target == name));
+    }
+
+    // Run every target a single time, returns the results sorted by target key
+    pub async fn check_once(&self) -> Vec<Value> {
+        let mut targets: Vec<&Arc<Target>> = self.map.values().collect();
+        targets.sort_by(|a, b| a.key.cmp(&b.key));
+        future::join_all(targets.into_iter().map(|target| target.check_once())).await
+    }
 }
 
 pub struct Ranger {
-    map: Map,
+    nightforts: Vec<String>,
+    fanout: bool,
+    failback_interval: u64,
+    conf_path: String,
+    reload_interval: u64,
+    // Identity sent in hello
+    hello: String,
+    managed: bool,
+    started: u64,
+    heartbeat_interval: u64,
+    // Queue of checks when the concurrent checks are limited
+    max_concurrent_checks: usize,
+    checks: Option<Semaphore>,
+    self_metrics_path: String,
+    passive: Value,
+    statsd: Option<Arc<Statsd>>,
+    // Local config, and targets pushed from nightfort for managed rangers
+    local: Mutex<Value>,
+    remote: Mutex<Vec<Value>>,
+    targets: Mutex<HashMap<u16, Arc<Target>>>,
+    // Senders of current connections with nightfort, one slot for failover or one for each
+    // address for fanout, targets keep running while disconnected
+    messengers: Mutex<Vec<Outbox>>,
+    spools: Vec<Option<Spool>>,
+    spool: Value,
+}
+
+// Connection of a nightfort slot, frames to spool are kept in memory until written by the
+// spool flusher, so that no file io happens while holding the messengers lock
+#[derive(Default)]
+struct Outbox {
+    messenger: Option<Messenger>,
+    unsent: Vec<Dracarys>,
+}
+
+// Remove a stale socket at the path before binding, any other file there is left alone
+fn clear_socket(path: &str) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            let _ = fs::remove_file(path);
+            true
+        },
+        Ok(_) => {
+            error!("Passive socket path {} exists and is not a socket, skipping the passive socket", path);
+            false
+        },
+        Err(_) => true,
+    }
 }
 
 impl Ranger {
-    fn start_watch(&self, messenger: Messenger) {
-        for target in self.map.map.values() {
-            Self::watch_target(target.clone(), messenger.clone());
+    fn send(&self, msg: Dracarys) {
+        let mut messengers = self.messengers.lock().unwrap();
+        for (slot, outbox) in messengers.iter_mut().enumerate() {
+            let msg = match outbox.messenger {
+                Some(ref messenger) => match messenger.send(msg.clone()) {
+                    Ok(_) => continue,
+                    Err(mpsc::error::SendError(msg)) => msg,
+                },
+                None => msg.clone(),
+            };
+            // Keep reports and metrics for replay after reconnect
+            if self.spools[slot].is_some() {
+                outbox.unsent.push(msg);
+            }
+        }
+    }
+
+    // Write the unsent frames into the spools
+    fn flush_spools(&self) {
+        for (slot, spool) in self.spools.iter().enumerate() {
+            if let Some(ref spool) = spool {
+                spool.flush(|| self.messengers.lock().unwrap()[slot].unsent.drain(..).collect());
+            }
+        }
+    }
+
+    fn start_spool_flush(ranger: &Arc<Ranger>) {
+        if ranger.spools.iter().all(|spool| spool.is_none()) { return; }
+        let ranger = ranger.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep!(1000);
+                let ranger = ranger.clone();
+                if let Err(e) = task::spawn_blocking(move || ranger.flush_spools()).await {
+                    error!("Failed to flush spool, error: {}", e);
+                }
+            }
+        });
+    }
+
+    // First dependency of the target reported down, dependencies of dependencies are followed
+    // as well, so that a target is skipped when anything below it is down
+    fn failed_dependency(&self, target: &Target) -> Option<String> {
+        if target.depends_on.is_empty() { return None; }
+        let targets = self.targets.lock().unwrap();
+        let mut visited = HashSet::new();
+        visited.insert(target.id);
+        let mut pending: Vec<&String> = target.depends_on.iter().collect();
+        while let Some(name) = pending.pop() {
+            for other in targets.values() {
+                if &other.name != name && &other.key != name { continue; }
+                // Guard against dependency cycles
+                if !visited.insert(other.id) { continue; }
+                let state = other.state.lock().unwrap();
+                if state.skipped || state.reported.map(|health| health <= other.fail_health).unwrap_or(false) {
+                    return Some(other.key.clone());
+                }
+                pending.extend(other.depends_on.iter());
+            }
+        }
+        None
+    }
+
+    fn target_info(target: &Target) -> Dracarys {
+        Dracarys::Target {
+            id: target.id,
+            name: target.name.clone(),
+            paths: target.paths.clone(),
+            extra: target.extra.to_string(),
+            key: target.key.clone(),
+        }
+    }
+
+    fn start_watch(ranger: &Arc<Ranger>) {
+        for target in ranger.targets.lock().unwrap().values() {
+            Self::watch_target(ranger.clone(), target.clone());
         }
     }
 
-    pub fn watch_target(target: Arc<Target>, mut messenger: Messenger) {
+    pub fn watch_target(ranger: Arc<Ranger>, target: Arc<Target>) {
         tokio::spawn(async move {
             // Send target info
-            let _ = messenger.send(Dracarys::Target {
-                id: target.id,
-                name: target.name.clone(),
-                paths: target.paths.clone(),
-                extra: target.extra.to_string(),
-            });
+            ranger.send(Self::target_info(&target));
+            if let TargetCheckType::WatchPassive(ref watch) = target.check_type {
+                Self::watch_passive(&ranger, &target, watch).await;
+                info!("Stopped watching passive target {} with id {}", target.name, target.id);
+                return;
+            }
+            let started = utils::now();
             let mut last_check: u64 = 0;
             let mut health_status: u8 = 0;
-            let interval = target.interval;
             let check_health_status = match target.check_type {
                 TargetCheckType::WatchMetric => false,
                 _ => true
             };
             loop {
-                let sleep_s = (last_check + interval) as i64 - utils::now() as i64;
-                if sleep_s > 0 {
-                    sleep!(1000 * sleep_s as u64);
+                if target.retired.load(Ordering::SeqCst) { break; }
+                let next_check = target.next_check(last_check, started);
+                let sleep_s = next_check.map(|next| next as i64 - utils::now() as i64).unwrap_or(0);
+                let (paused, run_now) = {
+                    let control = target.control.lock().unwrap();
+                    (control.paused, control.run_now)
+                };
+                if !run_now && (paused || next_check.is_none() || sleep_s > 0) {
+                    // Sleep until next check or any command
+                    if paused || next_check.is_none() {
+                        target.wake.notified().await;
+                    } else {
+                        tokio::select! {
+                            _ = time::delay_for(Duration::from_secs(sleep_s as u64)) => {},
+                            _ = target.wake.notified() => {},
+                        }
+                    }
+                    continue;
                 }
+                target.control.lock().unwrap().run_now = false;
+
+                // Skip the check while a dependency is down
+                let dependency = if check_health_status { ranger.failed_dependency(&target) } else { None };
+                let skipped = {
+                    let mut state = target.state.lock().unwrap();
+                    let changed = state.skipped != dependency.is_some();
+                    state.skipped = dependency.is_some();
+                    changed
+                };
+                if skipped {
+                    let info = match dependency {
+                        Some(ref dependency) => format!("Skipping checks of target {} as dependency {} is down", target.key, dependency),
+                        None => format!("Resuming checks of target {} as dependencies recovered", target.key),
+                    };
+                    info!("{}", info);
+                    ranger.send(Dracarys::Message { id: target.id, data: utils::tidings("dependency", &info) });
+                }
+                if dependency.is_some() {
+                    last_check = utils::now();
+                    target.state.lock().unwrap().last_check = last_check;
+                    ranger.send(Dracarys::Report {
+                        id: target.id,
+                        health_status: target.dependency_health,
+                        timestamp: last_check,
+                        flags: REPORT_DEPENDENCY_FAILED,
+                    });
+                    continue;
+                }
+
+                // Wait in the queue for a free check slot
+                let queued = Instant::now();
+                let permit = match ranger.checks {
+                    Some(ref checks) => Some(checks.acquire().await),
+                    None => None,
+                };
+                if permit.is_some() {
+                    ranger.send(Dracarys::Metric {
+                        id: 0,
+                        relative: false,
+                        metrics: vec![(format!("{}.check_queue_wait", ranger.self_metrics_path), queued.elapsed().as_millis().to_string(), utils::now())],
+                    });
+                }
+
                 last_check = utils::now();
                 let mut metrics = Vec::new();
-                match target.check_health(&mut health_status, &mut metrics).await {
+                let mut messages = Vec::new();
+                let res = target.check_health(&mut health_status, &mut metrics, &mut messages).await;
+                drop(permit);
+                match res {
                     Ok(_) => {
+                        if target.retired.load(Ordering::SeqCst) { break; }
                         if check_health_status {
-                            {
+                            let (reported, flags) = {
                                 let mut state = target.state.lock().unwrap();
                                 state.last_check = last_check;
-                                state.health_status = health_status;
-                                state.health_history.push_back(health_status);
-                                if state.health_history.len() > 50 {
-                                    state.health_history.pop_front();
-                                }
-                            }
+                                target.settle(&mut state, health_status, &mut messages)
+                            };
                             // Send report
-                            let _ = messenger.send(Dracarys::Report {
+                            ranger.send(Dracarys::Report {
                                 id: target.id,
-                                health_status,
+                                health_status: reported,
+                                timestamp: last_check,
+                                flags,
                             });
                         }
         
-                        if metrics.len() > 0 {
-                            let now = utils::now();
-                            let mut data = Vec::new();
-                            for m in metrics.iter() {
-                                data.push((m.0.clone(), m.1.clone(), now));
-                            }
-                            let _ = messenger.send(Dracarys::Metric {
+                        // Metric frame holds at most 255 metrics
+                        for chunk in metrics.chunks(255) {
+                            ranger.send(Dracarys::Metric {
                                 id: target.id,
                                 relative: target.relative_metric_path,
-                                metrics: data,
+                                metrics: chunk.to_vec(),
+                            });
+                        }
+
+                        for data in messages.drain(..) {
+                            ranger.send(Dracarys::Message {
+                                id: target.id,
+                                data,
                             });
                         }
                     },
@@ -339,12 +1060,319 @@ impl Ranger {
                     },
                 }
             }
+            info!("Stopped watching target {} with id {}", target.name, target.id);
+        });
+    }
+
+    // Apply a new configuration, only the added, removed or changed targets are touched
+    pub fn reload(ranger: &Arc<Ranger>, raw: &Value) {
+        let known = ranger.target_ids();
+        let map = Map::with_ids(raw, &known);
+        if map.nightforts != ranger.nightforts || map.fanout != ranger.fanout {
+            warn!("Nightfort addresses changed to {:?}, which only takes effect after restart", map.nightforts);
+        }
+        if map.max_concurrent_checks != ranger.max_concurrent_checks {
+            warn!("Max concurrent checks changed to {}, which only takes effect after restart", map.max_concurrent_checks);
+        }
+        // Frames are sent after releasing the targets lock, which is never held while locking messengers
+        let mut retired = Vec::new();
+        let mut started = Vec::new();
+        let mut targets = ranger.targets.lock().unwrap();
+        let ids: Vec<u16> = targets.keys().cloned().collect();
+        for id in ids {
+            let old = targets[&id].clone();
+            let retire = match map.map.get(&id) {
+                Some(target) => {
+                    if target.raw == old.raw { continue; }
+                    // Leaf node can be kept if only the check changes
+                    target.key != old.key || target.name != old.name || target.paths != old.paths
+                },
+                None => true,
+            };
+            info!("Stopping target {} with id {} for configuration change", old.name, id);
+            old.retired.store(true, Ordering::SeqCst);
+            old.wake.notify();
+            targets.remove(&id);
+            if retire {
+                retired.push(id);
+            }
+        }
+        for (id, target) in map.map.into_iter() {
+            if targets.contains_key(&id) { continue; }
+            info!("Starting target {} with id {}", target.name, id);
+            targets.insert(id, target.clone());
+            started.push(target);
+        }
+        drop(targets);
+        for id in retired {
+            ranger.send(Dracarys::Retire { id });
+        }
+        for target in started {
+            Self::watch_target(ranger.clone(), target);
+        }
+        spool::save_ids(&ranger.spool, &ranger.target_ids());
+    }
+
+    fn target_ids(&self) -> HashMap<String, u16> {
+        self.targets.lock().unwrap().values().map(|target| (target.key.clone(), target.id)).collect()
+    }
+
+    fn load_config(ranger: &Arc<Ranger>) {
+        info!("Reloading configuration from {}", ranger.conf_path);
+        let raw: Value = match fs::File::open(&ranger.conf_path) {
+            Ok(file) => match serde_json::from_reader(file) {
+                Ok(raw) => raw,
+                Err(e) => {
+                    error!("Failed to parse config file {}, keep current targets, error: {}", ranger.conf_path, e);
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("Failed to read config file {}, keep current targets, error: {}", ranger.conf_path, e);
+                return;
+            }
+        };
+        *ranger.local.lock().unwrap() = raw;
+        Self::apply(ranger);
+    }
+
+    // Apply local config together with the targets from nightfort
+    fn apply(ranger: &Arc<Ranger>) {
+        let mut raw = ranger.local.lock().unwrap().clone();
+        let mut targets = raw["targets"].as_array().cloned().unwrap_or_default();
+        targets.extend(ranger.remote.lock().unwrap().iter().cloned());
+        raw["targets"] = Value::Array(targets);
+        Self::reload(ranger, &raw);
+    }
+
+    // Report the stale health when no push arrives in time
+    async fn watch_passive(ranger: &Arc<Ranger>, target: &Arc<Target>, watch: &PassiveWatch) {
+        let started = utils::now();
+        let mut stale_for = None;
+        loop {
+            if target.retired.load(Ordering::SeqCst) { break; }
+            let last_push = target.state.lock().unwrap().last_check.max(started);
+            let now = utils::now();
+            if watch.stale_after == 0 || stale_for == Some(last_push) {
+                target.wake.notified().await;
+                continue;
+            }
+            if now < last_push + watch.stale_after {
+                tokio::select! {
+                    _ = time::delay_for(Duration::from_secs(last_push + watch.stale_after - now)) => {},
+                    _ = target.wake.notified() => {},
+                }
+                continue;
+            }
+            stale_for = Some(last_push);
+            let info = format!("No push for passive target {} in {} seconds", target.key, now - last_push);
+            warn!("{}", info);
+            let mut messages = vec![utils::tidings("stale", &info)];
+            let (reported, flags) = target.settle(&mut target.state.lock().unwrap(), watch.stale_health, &mut messages);
+            ranger.send(Dracarys::Report { id: target.id, health_status: reported, timestamp: now, flags });
+            for data in messages.drain(..) {
+                ranger.send(Dracarys::Message { id: target.id, data });
+            }
+        }
+    }
+
+    // Forward a push to the passive target
+    fn push(&self, push: Push) {
+        let target = self.targets.lock().unwrap().values().find(|target| {
+            (target.name == push.target || target.key == push.target) && match target.check_type {
+                TargetCheckType::WatchPassive(_) => true,
+                _ => false,
+            }
+        }).cloned();
+        let target = match target {
+            Some(target) => target,
+            None => {
+                warn!("Dropped push for unknown passive target {}", push.target);
+                return;
+            }
+        };
+        let now = utils::now();
+        let mut messages = push.messages;
+        if let Some(health) = push.health {
+            let (reported, flags) = {
+                let mut state = target.state.lock().unwrap();
+                state.last_check = now;
+                target.settle(&mut state, health, &mut messages)
+            };
+            self.send(Dracarys::Report { id: target.id, health_status: reported, timestamp: now, flags });
+        }
+        for chunk in push.metrics.chunks(255) {
+            self.send(Dracarys::Metric {
+                id: target.id,
+                relative: target.relative_metric_path,
+                metrics: chunk.to_vec(),
+            });
+        }
+        for data in messages.drain(..) {
+            self.send(Dracarys::Message { id: target.id, data });
+        }
+        target.wake.notify();
+    }
+
+    // Listen on a unix socket and udp for lines pushed by local applications
+    fn listen_passive(ranger: &Arc<Ranger>) {
+        let path = ranger.passive.get_str("socket", "");
+        if !path.is_empty() && clear_socket(&path) {
+            match UnixListener::bind(&path) {
+                Ok(mut listener) => {
+                    if let Ok(mode) = u32::from_str_radix(&ranger.passive.get_str("socket_mode", "660"), 8) {
+                        if let Err(e) = fs::set_permissions(&path, fs::Permissions::from_mode(mode)) {
+                            error!("Failed to set mode of passive socket {}, error: {}", path, e);
+                        }
+                    }
+                    info!("Listening for passive pushes at {}", path);
+                    let ranger = ranger.clone();
+                    tokio::spawn(async move {
+                        loop {
+                            let stream = match listener.accept().await {
+                                Ok((stream, _)) => stream,
+                                Err(e) => {
+                                    error!("Failed to accept passive connection, error: {}", e);
+                                    continue;
+                                }
+                            };
+                            let ranger = ranger.clone();
+                            tokio::spawn(async move {
+                                let mut lines = BufReader::new(stream).lines();
+                                while let Ok(Some(line)) = lines.next_line().await {
+                                    if let Some(push) = passive::parse_push(&line) {
+                                        ranger.push(push);
+                                    }
+                                }
+                            });
+                        }
+                    });
+                },
+                Err(e) => error!("Failed to listen for passive pushes at {}, error: {}", path, e),
+            }
+        }
 
+        let addr = ranger.passive.get_str("udp", "");
+        if !addr.is_empty() {
+            let ranger = ranger.clone();
+            tokio::spawn(async move {
+                let mut socket = match UdpSocket::bind(&addr).await {
+                    Ok(socket) => socket,
+                    Err(e) => {
+                        error!("Failed to listen for passive pushes at udp {}, error: {}", addr, e);
+                        return;
+                    }
+                };
+                info!("Listening for passive pushes at udp {}", addr);
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    match socket.recv_from(&mut buf).await {
+                        Ok((size, _)) => {
+                            for line in String::from_utf8_lossy(&buf[..size]).lines() {
+                                if let Some(push) = passive::parse_push(line) {
+                                    ranger.push(push);
+                                }
+                            }
+                        },
+                        Err(e) => error!("Failed to receive passive push, error: {}", e),
+                    }
+                }
+            });
+        }
+    }
+
+    // Aggregate statsd metrics and forward them on every flush
+    fn listen_statsd(ranger: &Arc<Ranger>) {
+        let statsd = match ranger.statsd {
+            Some(ref statsd) => statsd.clone(),
+            None => return,
+        };
+        let receiver = statsd.clone();
+        tokio::spawn(async move {
+            let mut socket = match UdpSocket::bind(&receiver.bind).await {
+                Ok(socket) => socket,
+                Err(e) => {
+                    error!("Failed to listen for statsd at {}, error: {}", receiver.bind, e);
+                    return;
+                }
+            };
+            info!("Listening for statsd at {}", receiver.bind);
+            let mut buf = vec![0u8; 65536];
+            loop {
+                match socket.recv_from(&mut buf).await {
+                    Ok((size, _)) => receiver.ingest(&String::from_utf8_lossy(&buf[..size])),
+                    Err(e) => error!("Failed to receive statsd packet, error: {}", e),
+                }
+            }
+        });
+
+        let ranger = ranger.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep!(1000 * statsd.flush_interval);
+                let metrics = statsd.flush(utils::now());
+                // Metric frame holds at most 255 metrics
+                for chunk in metrics.chunks(255) {
+                    ranger.send(Dracarys::Metric {
+                        id: 0,
+                        relative: false,
+                        metrics: chunk.to_vec(),
+                    });
+                }
+            }
+        });
+    }
+
+    fn start_heartbeat(ranger: &Arc<Ranger>) {
+        if ranger.heartbeat_interval == 0 { return; }
+        let ranger = ranger.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep!(1000 * ranger.heartbeat_interval);
+                ranger.send(Dracarys::Heartbeat { uptime: utils::now() - ranger.started });
+            }
+        });
+    }
+
+    // Reload the configuration on SIGHUP or when the file gets modified
+    fn watch_config(ranger: &Arc<Ranger>) {
+        let hup_ranger = ranger.clone();
+        tokio::spawn(async move {
+            match signal(SignalKind::hangup()) {
+                Ok(mut hup) => {
+                    while let Some(_) = hup.recv().await {
+                        info!("Received SIGHUP");
+                        Self::load_config(&hup_ranger);
+                    }
+                },
+                Err(e) => error!("Failed to listen for SIGHUP, error: {}", e),
+            }
+        });
+
+        if ranger.reload_interval == 0 { return; }
+        let ranger = ranger.clone();
+        tokio::spawn(async move {
+            let modified = |path: &String| fs::metadata(path).and_then(|m| m.modified()).ok();
+            let mut last_modified = modified(&ranger.conf_path);
+            loop {
+                sleep!(1000 * ranger.reload_interval);
+                let current = modified(&ranger.conf_path);
+                if current.is_some() && current != last_modified {
+                    last_modified = current;
+                    Self::load_config(&ranger);
+                }
+            }
         });
     }
 }
 
-impl Wine<Dracarys> for Arc<Ranger> {
+// Connection of the ranger with one nightfort slot
+pub struct Courier {
+    ranger: Arc<Ranger>,
+    slot: usize,
+}
+
+impl Wine<Dracarys> for Courier {
     type Stream = mpsc::UnboundedReceiver<Dracarys>;
     type Framer = DracarysFramer;
     fn get_framer(&self) -> Self::Framer {
@@ -352,35 +1380,277 @@ impl Wine<Dracarys> for Arc<Ranger> {
     }
 
     fn drink(&self, msg: Dracarys) {
-        info!("Received message for the ranger: {:?}", msg);
+        match msg {
+            Dracarys::Error { id, ref message } => {
+                let targets = self.ranger.targets.lock().unwrap();
+                let name = targets.get(&id).map(|t| t.key.clone()).unwrap_or_default();
+                error!("Nightfort rejected target {} with id {}, error: {}", name, id, message);
+            },
+            Dracarys::Config { ref data } => {
+                if !self.ranger.managed {
+                    warn!("Ignored targets pushed from nightfort for unmanaged ranger");
+                    return;
+                }
+                match serde_json::from_str::<Value>(data) {
+                    Ok(config) => {
+                        let targets = config["targets"].as_array().cloned().unwrap_or_default();
+                        info!("Received {} targets from nightfort", targets.len());
+                        *self.ranger.remote.lock().unwrap() = targets;
+                        Ranger::apply(&self.ranger);
+                    },
+                    Err(e) => error!("Failed to parse targets pushed from nightfort, error: {}", e),
+                }
+            },
+            Dracarys::Command { id, seq, ref command } => {
+                info!("Received command {:?} for target with id {}", command, id);
+                let ack = match self.ranger.targets.lock().unwrap().get(&id) {
+                    Some(target) => Dracarys::Ack { id, seq, success: true, message: target.command(command) },
+                    None => Dracarys::Ack { id, seq, success: false, message: format!("No target with id {}", id) },
+                };
+                if let Some(ref messenger) = self.ranger.messengers.lock().unwrap()[self.slot].messenger {
+                    let _ = messenger.send(ack);
+                }
+            },
+            _ => info!("Received message for the ranger: {:?}", msg),
+        }
     }
 
-    fn take_nap(&self) {
+    fn take_nap(&self, mut stream: Self::Stream) {
         info!("Ranger lost connection for the moment!");
+        let mut messengers = self.ranger.messengers.lock().unwrap();
+        let outbox = &mut messengers[self.slot];
+        outbox.messenger = None;
+        // Frames queued for the lost connection go to the spool as well
+        if self.ranger.spools[self.slot].is_some() {
+            let mut unsent = Vec::new();
+            while let Ok(msg) = stream.try_recv() {
+                unsent.push(msg);
+            }
+            unsent.extend(outbox.unsent.drain(..));
+            outbox.unsent = unsent;
+        }
     }
 
     fn wake_up(&self) -> Self::Stream {
         info!("Ranger gets connected with Nightfort");
         let (tx, rx) = mpsc::unbounded_channel();
-        // self.messenger = Some(tx);
-        self.start_watch(tx);
+        let _ = tx.send(Dracarys::Hello { data: self.ranger.hello.clone() });
+        // Register running targets again before any report on the new connection, the targets
+        // lock is never held while locking messengers
+        let infos: Vec<Dracarys> = self.ranger.targets.lock().unwrap().values().map(|target| Ranger::target_info(target)).collect();
+        for info in infos {
+            let _ = tx.send(info);
+        }
+        // Replay spooled frames before the frames not yet spooled, the spool lock keeps the
+        // flusher from writing frames after they are read
+        let install = |frames: Vec<Dracarys>| {
+            let mut messengers = self.ranger.messengers.lock().unwrap();
+            let outbox = &mut messengers[self.slot];
+            for msg in frames.into_iter().chain(outbox.unsent.drain(..)) {
+                let msg = match msg {
+                    Dracarys::Report { id, health_status, timestamp, flags } =>
+                        Dracarys::Report { id, health_status, timestamp, flags: flags | REPORT_REPLAYED },
+                    msg => msg,
+                };
+                let _ = tx.send(msg);
+            }
+            outbox.messenger = Some(tx);
+        };
+        match self.ranger.spools[self.slot] {
+            Some(ref spool) => spool.replay(install),
+            None => install(Vec::new()),
+        }
         rx
     }
 }
 
 impl Ranger {
-    pub fn new(map: &Value) -> Ranger {
+    pub fn new(raw: &Value, conf_path: &str) -> Ranger {
+        let map = Map::with_ids(raw, &spool::load_ids(&raw["spool"]));
+        spool::save_ids(&raw["spool"], &map.map.values().map(|target| (target.key.clone(), target.id)).collect());
+        let managed = raw.get_bool("managed", raw["targets"].is_null());
+        let started = utils::now();
+        let hostname = raw.get_str("hostname", &utils::hostname());
+        // Spool for each address with fanout
+        let spools: Vec<Option<Spool>> = if map.fanout {
+            map.nightforts.iter().map(|addr| Spool::new(&raw["spool"], &addr.replace(':', "_"))).collect()
+        } else {
+            vec![Spool::new(&raw["spool"], "")]
+        };
         Ranger {
-            map: Map::new(map),
+            nightforts: map.nightforts,
+            fanout: map.fanout,
+            failback_interval: map.failback_interval,
+            conf_path: conf_path.to_string(),
+            reload_interval: map.reload_interval,
+            hello: json!({
+                "hostname": hostname,
+                "labels": raw["labels"],
+                "managed": managed,
+                "version": env!("CARGO_PKG_VERSION"),
+                "pid": std::process::id(),
+                "started": started,
+            }).to_string(),
+            managed,
+            started,
+            heartbeat_interval: raw.get_u64("heartbeat_interval", 10),
+            max_concurrent_checks: map.max_concurrent_checks,
+            checks: if map.max_concurrent_checks > 0 { Some(Semaphore::new(map.max_concurrent_checks)) } else { None },
+            self_metrics_path: raw.get_str("self_metrics_path", &format!(".rangers.{}", hostname.replace('.', "_"))),
+            passive: raw["passive"].clone(),
+            statsd: Statsd::new(&raw["statsd"], &format!(".statsd.{}", hostname.replace('.', "_"))).map(Arc::new),
+            local: Mutex::new(raw.clone()),
+            remote: Mutex::new(Vec::new()),
+            targets: Mutex::new(map.map),
+            messengers: Mutex::new(spools.iter().map(|_| Outbox::default()).collect()),
+            spools,
+            spool: raw["spool"].clone(),
         }
     }
 
     pub async fn start(self) -> AsyncRes {
+        if self.nightforts.is_empty() {
+            return Err("No nightfort address configured".into());
+        }
         let ranger = Arc::new(self);
-        // tokio::spawn(async move {
-        Knight::new(&ranger.map.nightfort, ranger.clone()).drink_wine().await.unwrap();
-        // });
+        Self::start_watch(&ranger);
+        Self::watch_config(&ranger);
+        Self::start_heartbeat(&ranger);
+        Self::start_spool_flush(&ranger);
+        Self::listen_passive(&ranger);
+        Self::listen_statsd(&ranger);
+        if ranger.fanout {
+            let mut knights = Vec::new();
+            for (slot, nightfort) in ranger.nightforts.iter().enumerate() {
+                let courier = Courier { ranger: ranger.clone(), slot };
+                knights.push(Knight::new(&vec![nightfort.clone()], 0, courier).drink_wine());
+            }
+            for res in future::join_all(knights).await {
+                res?;
+            }
+        } else {
+            let courier = Courier { ranger: ranger.clone(), slot: 0 };
+            Knight::new(&ranger.nightforts, ranger.failback_interval, courier).drink_wine().await?;
+        }
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_id_independent_of_order() {
+        let a = json!({ "name": "a", "paths": [".app.x"], "watch": { "type": "watch_exit", "prog": "true" } });
+        let b = json!({ "id": "b-check", "name": "b", "paths": [".app.x"], "watch": { "type": "watch_exit", "prog": "true" } });
+        let c = json!({ "name": "c", "paths": [".app.y"], "watch": { "type": "watch_exit", "prog": "true" } });
+        let ids = |targets: Value| {
+            let map = Map::new(&json!({ "targets": targets }));
+            let mut ids: Vec<(String, u16)> = map.map.values().map(|t| (t.key.clone(), t.id)).collect();
+            ids.sort();
+            ids
+        };
+        let first = ids(json!([a, b, c]));
+        assert_eq!(first.len(), 3);
+        assert_eq!(first, ids(json!([c, a, b])));
+        assert_eq!(first[0].0, "a@.app.x");
+        assert_eq!(first[1].0, "b-check");
+
+        // Duplicated targets are dropped
+        assert_eq!(ids(json!([a, a])).len(), 1);
+    }
+
+    #[test]
+    fn test_config_problems() {
+        let mut map = Map::new(&json!({ "targets": [
+            { "name": "ok", "paths": [".app.x"], "watch": { "type": "watch_exit", "prog": "true" } },
+            { "name": "native", "paths": [".app.x"], "watch": { "type": "watch_tcp" } },
+            { "name": "orphan", "paths": [], "watch": { "type": "watch_exit", "prog": "true" } },
+            { "name": "typo", "paths": [".app.x"], "watch": { "type": "watch_exits", "prog": "true" } },
+            { "name": "noprog", "paths": [".app.x"], "watch": { "type": "watch_json" } },
+            { "name": "never", "paths": [".app.x"], "cron": "0 0 31 2 *", "watch": { "type": "watch_exit", "prog": "true" } },
+            { "name": "negative", "paths": [".app.x"], "watch": { "type": "watch_tcp", "timeout": -1 } },
+            { "name": "regex", "paths": [".app.x"], "watch": { "type": "watch_http", "body_regex": "(" } },
+            { "name": "nowatch", "paths": [".app.x"] },
+            { "name": "output", "paths": [".app.x"], "watch": {} },
+        ]}));
+        assert_eq!(map.map.len(), 2);
+        assert_eq!(map.problems.len(), 8);
+        assert!(map.problems[5].info.contains("invalid regex"));
+        assert!(map.problems[3].info.contains("never matches"));
+        assert!(map.problems[1].info.contains("unknown watch type watch_exits"));
+
+        map.select("typo");
+        assert!(map.map.is_empty());
+        assert_eq!(map.problems.len(), 1);
+    }
+
+    #[test]
+    fn test_stable_ids() {
+        // Find two keys with colliding ids
+        let mut seen = HashMap::new();
+        let (first, second) = (0..).find_map(|i| {
+            let key = format!("target-{}", i);
+            seen.insert(utils::hash_u16(&key), key.clone()).map(|other| if other < key { (other, key) } else { (key, other) })
+        }).unwrap();
+        let target = |key: &str| json!({ "id": key, "paths": [".app.x"], "watch": { "type": "watch_exit", "prog": "true" } });
+        let ids = |map: &Map| map.map.values().map(|t| (t.key.clone(), t.id)).collect::<HashMap<String, u16>>();
+        let before = ids(&Map::new(&json!({ "targets": [target(&second)] })));
+        // Adding a target sorted before it with the same hash does not take its id
+        let after = ids(&Map::with_ids(&json!({ "targets": [target(&first), target(&second)] }), &before));
+        assert_eq!(after[&second], before[&second]);
+        assert_ne!(after[&first], after[&second]);
+    }
+
+    #[test]
+    fn test_temporary_interval() {
+        let map = Map::new(&json!({ "targets": [{ "name": "t", "paths": [".app.x"], "interval": 30, "watch": { "type": "watch_exit", "prog": "true" } }]}));
+        let target = map.map.values().next().unwrap();
+        let now = utils::now();
+        target.command(&RangerCommand::SetInterval { interval: 3600, duration: 60 });
+        // Sleeps only until the temporary interval expires
+        let next = target.next_check(now, now).unwrap();
+        assert!(next >= now + 60 && next <= now + 61);
+        target.command(&RangerCommand::SetInterval { interval: 0, duration: 0 });
+        assert_eq!(target.next_check(now, now), Some(now + 30));
+    }
+
+    #[test]
+    fn test_transitive_dependency() {
+        let target = |name: &str, depends_on: Vec<&str>| json!({ "name": name, "paths": [".app.x"], "depends_on": depends_on, "watch": { "type": "watch_exit", "prog": "true" } });
+        let ranger = Ranger::new(&json!({ "targets": [
+            target("a", vec![]), target("b", vec!["a"]), target("c", vec!["b"]),
+            target("d", vec!["e"]), target("e", vec!["d"]),
+        ]}), "");
+        let find = |name: &str| ranger.targets.lock().unwrap().values().find(|t| t.name == name).cloned().unwrap();
+        assert_eq!(ranger.failed_dependency(&find("c")), None);
+        find("a").state.lock().unwrap().reported = Some(0);
+        assert_eq!(ranger.failed_dependency(&find("c")), Some(find("a").key.clone()));
+        // Cycles end the walk
+        assert_eq!(ranger.failed_dependency(&find("d")), None);
+    }
+
+    #[test]
+    fn test_flap_suppression() {
+        let map = Map::new(&json!({ "targets": [
+            { "name": "flaky", "paths": [".app.x"], "down_after": 2, "up_after": 2, "flap_window": 6, "flap_threshold": 3, "watch": { "type": "watch_exit", "prog": "true" } },
+        ]}));
+        let target = map.map.values().next().unwrap();
+        let mut state = target.state.lock().unwrap();
+        let mut messages = Vec::new();
+        let mut settle = |health| target.settle(&mut state, health, &mut messages);
+        assert_eq!(settle(100), (100, 0));
+        // A single failure is held back until confirmed
+        assert_eq!(settle(0), (100, 0));
+        assert_eq!(settle(0), (0, 0));
+        assert_eq!(settle(100), (0, 0));
+        assert_eq!(settle(100), (100, 0));
+        // Alternating results mark the target flapping
+        assert_eq!(settle(0), (100, REPORT_FLAPPING));
+        assert_eq!(settle(100), (100, REPORT_FLAPPING));
+        for _ in 0..6 { settle(100); }
+        assert_eq!(settle(100), (100, 0));
+        assert_eq!(messages.len(), 2);
+    }
+}
@@ -24,6 +24,8 @@ SOFTWARE.
 use crate::event::Event;
 use crate::alert::Alert;
 use serde_json::{self, Value};
+use crate::dracarys::RangerCommand;
+use crate::utils::JsonParser;
 
 pub enum RavenMessage<'a> {
     TakeSnapshot,
@@ -34,6 +36,22 @@ pub enum RavenMessage<'a> {
     NewEvent {
         data: &'a Event,
     },
+    // Sample: {"method": "ranger_command", "data": {"path": ".app.service.leaf", "command": "set_interval", "interval": 5, "duration": 600}}
+    // Commands: run_now, pause, resume, set_interval
+    RangerCommand {
+        path: String,
+        command: RangerCommand,
+    },
+    RangerCommandSent {
+        data: &'a Value,
+    },
+    RangerAck {
+        data: &'a Value,
+    },
+    ListRangers,
+    Rangers {
+        data: &'a Value,
+    },
     None,
 }
 
@@ -60,6 +78,27 @@ impl<'a> From<&RavenMessage<'a>> for Value {
                     "data": data
                 })
             },
+            RavenMessage::RangerCommandSent { data } => {
+                json!({
+                    "method": "ranger_command_sent",
+                    "data": data
+                })
+            },
+            RavenMessage::RangerAck { data } => {
+                json!({
+                    "method": "ranger_ack",
+                    "data": data
+                })
+            },
+            RavenMessage::ListRangers => {
+                json!({"method": "list_rangers"})
+            },
+            RavenMessage::Rangers { data } => {
+                json!({
+                    "method": "rangers",
+                    "data": data
+                })
+            },
             _ => unimplemented!()
         }
     }
@@ -73,6 +112,24 @@ impl<'a> From<&String> for RavenMessage<'a> {
                     Some(method) => {
                         if method == "take_snapshot" {
                             RavenMessage::TakeSnapshot
+                        } else if method == "list_rangers" {
+                            RavenMessage::ListRangers
+                        } else if method == "ranger_command" {
+                            let data = &value["data"];
+                            let command = match data.get_str("command", "").as_str() {
+                                "run_now" => RangerCommand::RunNow,
+                                "pause" => RangerCommand::Pause,
+                                "resume" => RangerCommand::Resume,
+                                "set_interval" => RangerCommand::SetInterval {
+                                    interval: data.get_u64("interval", 0) as u32,
+                                    duration: data.get_u64("duration", 0) as u32,
+                                },
+                                command => {
+                                    error!("Unknown ranger command: {}", command);
+                                    return RavenMessage::None;
+                                }
+                            };
+                            RavenMessage::RangerCommand { path: data.get_str("path", ""), command }
                         } else {
                             error!("Unknown rave message method: {}", method);
                             RavenMessage::None
@@ -0,0 +1,210 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser};
+use crate::dracarys::{Dracarys, DracarysFramer};
+use tokio_util::codec::{Encoder, Decoder};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::collections::HashMap;
+
+// Sample configuration
+//
+// spool:
+//   path: /var/lib/nightswatch/spool
+//   max_bytes: 10485760     # total size of the spool files
+//   max_age: 3600           # seconds, older frames are dropped on replay
+//
+// Reports and metrics are spooled as encoded frames into two segments, when the current
+// segment is full the previous one is dropped, so that the newest data is kept.
+//
+// Target ids are kept in `ids.json` under the spool path, so that spooled frames still refer
+// to the same targets after a restart.
+//
+
+// Target ids assigned by the last run, by target key
+pub fn load_ids(raw: &Value) -> HashMap<String, u16> {
+    let path = raw.get_str("path", "");
+    if path.is_empty() { return HashMap::new(); }
+    fs::read(PathBuf::from(path).join("ids.json")).ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_ids(raw: &Value, ids: &HashMap<String, u16>) {
+    let path = raw.get_str("path", "");
+    if path.is_empty() { return; }
+    let path = PathBuf::from(path).join("ids.json");
+    let res = fs::create_dir_all(path.parent().unwrap())
+        .and_then(|_| fs::write(&path, serde_json::to_vec(ids).unwrap_or_default()));
+    if let Err(e) = res {
+        error!("Failed to write target ids file {:?}, error: {}", path, e);
+    }
+}
+
+pub struct Spool {
+    path: PathBuf,
+    max_bytes: u64,
+    max_age: u64,
+    lock: Mutex<()>,
+}
+
+impl Spool {
+    pub fn new(raw: &Value, name: &str) -> Option<Spool> {
+        let path = raw.get_str("path", "");
+        if path.is_empty() { return None; }
+        let path = PathBuf::from(path).join(name);
+        if let Err(e) = fs::create_dir_all(&path) {
+            error!("Failed to create spool directory {:?}, error: {}", path, e);
+            return None;
+        }
+        Some(Spool {
+            path,
+            max_bytes: raw.get_u64("max_bytes", 10 * 1024 * 1024),
+            max_age: raw.get_u64("max_age", 3600),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn segment(&self, index: u8) -> PathBuf {
+        self.path.join(format!("spool.{}", index))
+    }
+
+    // Time when the data in the frame was collected
+    fn timestamp(msg: &Dracarys) -> Option<u64> {
+        match msg {
+            Dracarys::Report { timestamp, .. } => Some(*timestamp),
+            Dracarys::Metric { ref metrics, .. } => metrics.iter().map(|m| m.2).max(),
+            _ => None,
+        }
+    }
+
+    // Append the frames taken under the spool lock, so that no frame is written after a replay
+    // that should have included it
+    pub fn flush<F: FnOnce() -> Vec<Dracarys>>(&self, take: F) {
+        let _lock = self.lock.lock().unwrap();
+        let mut data = bytes::BytesMut::new();
+        let mut framer = DracarysFramer::new();
+        for msg in take().into_iter() {
+            if Self::timestamp(&msg).is_none() { continue; }
+            if let Err(e) = framer.encode(msg, &mut data) {
+                error!("Failed to encode frame for spool, error: {}", e);
+            }
+        }
+        if data.is_empty() { return; }
+
+        let current = self.segment(1);
+        let size = fs::metadata(&current).map(|m| m.len()).unwrap_or(0);
+        if size + data.len() as u64 > self.max_bytes / 2 {
+            // Drop the oldest segment
+            let _ = fs::rename(&current, self.segment(0));
+        }
+        let res = OpenOptions::new().create(true).append(true).open(&current)
+            .and_then(|mut file| file.write_all(&data));
+        if let Err(e) = res {
+            error!("Failed to write spool file {:?}, error: {}", current, e);
+        }
+    }
+
+    // Hand over all spooled frames not older than max age, oldest first, while holding the spool
+    // lock
+    pub fn replay<R, F: FnOnce(Vec<Dracarys>) -> R>(&self, deliver: F) -> R {
+        let _lock = self.lock.lock().unwrap();
+        let mut frames = Vec::new();
+        let now = utils::now();
+        let mut expired = 0;
+        for index in 0..2 {
+            let segment = self.segment(index);
+            let data = match fs::read(&segment) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            let _ = fs::remove_file(&segment);
+            let mut data = bytes::BytesMut::from(&data[..]);
+            let mut framer = DracarysFramer::new();
+            loop {
+                match framer.decode(&mut data) {
+                    Ok(Some(msg)) => {
+                        if Self::timestamp(&msg).unwrap_or(0) + self.max_age < now {
+                            expired += 1;
+                        } else {
+                            frames.push(msg);
+                        }
+                    },
+                    // Incomplete frame at the end
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Failed to decode spool file {:?}, error: {}", segment, e);
+                        break;
+                    }
+                }
+            }
+        }
+        if frames.len() > 0 || expired > 0 {
+            info!("Replaying {} spooled frames, {} expired frames dropped", frames.len(), expired);
+        }
+        deliver(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spool_replay() {
+        let dir = std::env::temp_dir().join(format!("nw-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let spool = Spool::new(&json!({ "path": dir.to_str().unwrap(), "max_bytes": 200, "max_age": 60 }), "").unwrap();
+        let now = utils::now();
+
+        spool.flush(|| vec![
+            Dracarys::Report { id: 1, health_status: 100, timestamp: now - 120, flags: 0 },
+            Dracarys::Message { id: 1, data: "skipped".to_string() },
+        ]);
+        for i in 0..20 {
+            spool.flush(|| vec![Dracarys::Report { id: 1, health_status: i, timestamp: now, flags: 0 }]);
+        }
+        spool.flush(|| vec![Dracarys::Metric { id: 1, relative: true, metrics: vec![(".m".to_string(), "1".to_string(), now)] }]);
+
+        let frames = spool.replay(|frames| frames);
+        // Oldest frames are dropped for the size limit
+        assert!(frames.len() < 21);
+        match frames.last() {
+            Some(Dracarys::Metric { metrics, .. }) => assert_eq!(metrics[0].2, now),
+            _ => panic!("Unexpected frame"),
+        }
+        match frames[frames.len() - 2] {
+            Dracarys::Report { health_status, timestamp, .. } => {
+                assert_eq!(health_status, 19);
+                assert_eq!(timestamp, now);
+            },
+            _ => panic!("Unexpected frame"),
+        }
+        assert!(spool.replay(|frames| frames).is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
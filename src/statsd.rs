@@ -0,0 +1,201 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use serde_json::Value;
+use crate::utils::JsonParser;
+
+// Sample configuration
+//
+// statsd:
+//   bind: 127.0.0.1:8125
+//   path: .apps.web-1        # base path of the metrics, .statsd.<hostname> by default
+//   flush_interval: 10       # seconds
+//   percentiles: [50, 90, 99]
+//
+// Accepted lines, several lines can be sent in one packet
+//
+//   requests:1|c|@0.1      counter with sample rate, flushed as .count and .rate per second
+//   queue.size:42|g        gauge, +N or -N changes the last value, kept across flushes
+//   latency:320|ms         timer or histogram (|h), flushed as count, min, max, mean, sum and percentiles
+//   users:alice|s          set, flushed as the number of unique values
+//
+
+#[derive(Default)]
+struct Aggregates {
+    counters: HashMap<String, f64>,
+    gauges: HashMap<String, f64>,
+    timers: HashMap<String, Vec<f64>>,
+    sets: HashMap<String, HashSet<String>>,
+}
+
+pub struct Statsd {
+    pub bind: String,
+    pub flush_interval: u64,
+    path: String,
+    percentiles: Vec<f64>,
+    state: Mutex<Aggregates>,
+}
+
+// Keep the metric name usable as a node path
+fn sanitize(name: &str) -> String {
+    name.trim().chars().map(|c| if c.is_alphanumeric() || c == '.' || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+fn format_value(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 { format!("{}", value as i64) } else { format!("{:.3}", value) }
+}
+
+impl Statsd {
+    pub fn new(raw: &Value, default_path: &str) -> Option<Statsd> {
+        let bind = raw.get_str("bind", "");
+        if bind.is_empty() { return None; }
+        let percentiles = match raw["percentiles"].as_array() {
+            Some(items) => items.iter().filter_map(|p| p.as_f64()).filter(|p| *p > 0.0 && *p <= 100.0).collect(),
+            None => vec![50.0, 90.0, 99.0],
+        };
+        Some(Statsd {
+            bind,
+            flush_interval: raw.get_u64("flush_interval", 10).max(1),
+            path: raw.get_str("path", default_path),
+            percentiles,
+            state: Mutex::new(Aggregates::default()),
+        })
+    }
+
+    pub fn ingest(&self, packet: &str) {
+        let mut state = self.state.lock().unwrap();
+        for line in packet.lines() {
+            let line = line.trim();
+            if line.is_empty() { continue; }
+            let (name, rest) = match line.find(':') {
+                Some(pos) => (sanitize(&line[..pos]), &line[pos+1..]),
+                None => {
+                    warn!("Invalid statsd line: {}", line);
+                    continue;
+                }
+            };
+            let fields: Vec<&str> = rest.split('|').collect();
+            if name.is_empty() || fields.len() < 2 {
+                warn!("Invalid statsd line: {}", line);
+                continue;
+            }
+            let value = fields[0].trim();
+            let kind = fields[1].trim();
+            if kind == "s" {
+                state.sets.entry(name).or_insert_with(HashSet::new).insert(value.to_string());
+                continue;
+            }
+            let number = match value.parse::<f64>() {
+                Ok(number) if number.is_finite() => number,
+                _ => {
+                    warn!("Invalid statsd value: {}", line);
+                    continue;
+                }
+            };
+            match kind {
+                "c" => {
+                    let rate = fields.get(2)
+                        .and_then(|field| field.trim().trim_start_matches('@').parse::<f64>().ok())
+                        .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+                        .unwrap_or(1.0);
+                    *state.counters.entry(name).or_insert(0.0) += number / rate;
+                },
+                "g" => {
+                    let gauge = state.gauges.entry(name).or_insert(0.0);
+                    if value.starts_with('+') || value.starts_with('-') {
+                        *gauge += number;
+                    } else {
+                        *gauge = number;
+                    }
+                },
+                "ms" | "h" => state.timers.entry(name).or_insert_with(Vec::new).push(number),
+                _ => warn!("Unsupported statsd metric type: {}", line),
+            }
+        }
+    }
+
+    // Aggregates since the last flush as metrics with absolute paths
+    pub fn flush(&self, now: u64) -> Vec<(String, String, u64)> {
+        let mut state = self.state.lock().unwrap();
+        let mut metrics = Vec::new();
+        let mut metric = |name: &str, suffix: &str, value: f64| {
+            metrics.push((format!("{}.{}{}", self.path, name, suffix), format_value(value), now));
+        };
+        for (name, count) in state.counters.drain() {
+            metric(&name, ".count", count);
+            metric(&name, ".rate", count / self.flush_interval as f64);
+        }
+        for (name, value) in state.gauges.iter() {
+            metric(name, "", *value);
+        }
+        for (name, mut values) in state.timers.drain() {
+            if values.is_empty() { continue; }
+            values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let count = values.len();
+            let sum: f64 = values.iter().sum();
+            metric(&name, ".count", count as f64);
+            metric(&name, ".min", values[0]);
+            metric(&name, ".max", values[count - 1]);
+            metric(&name, ".mean", sum / count as f64);
+            metric(&name, ".sum", sum);
+            for p in self.percentiles.iter() {
+                // Nearest rank
+                let rank = ((p / 100.0 * count as f64).ceil() as usize).max(1) - 1;
+                metric(&name, &format!(".p{}", format_value(*p).replace('.', "_")), values[rank.min(count - 1)]);
+            }
+        }
+        for (name, values) in state.sets.drain() {
+            metric(&name, ".count", values.len() as f64);
+        }
+        metrics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_statsd_aggregation() {
+        let statsd = Statsd::new(&json!({ "bind": "127.0.0.1:0", "path": ".apps", "flush_interval": 10 }), "").unwrap();
+        statsd.ingest("requests:1|c\nrequests:2|c|@0.5\nqueue:10|g\nqueue:-3|g\nusers:a|s\nusers:b|s\nusers:a|s\nbad line");
+        for i in 1..=100 {
+            statsd.ingest(&format!("latency:{}|ms", i));
+        }
+        let metrics: HashMap<String, String> = statsd.flush(1).into_iter().map(|(path, value, _)| (path, value)).collect();
+        assert_eq!(metrics[".apps.requests.count"], "5");
+        assert_eq!(metrics[".apps.requests.rate"], "0.500");
+        assert_eq!(metrics[".apps.queue"], "7");
+        assert_eq!(metrics[".apps.users.count"], "2");
+        assert_eq!(metrics[".apps.latency.count"], "100");
+        assert_eq!(metrics[".apps.latency.mean"], "50.500");
+        assert_eq!(metrics[".apps.latency.p90"], "90");
+        assert_eq!(metrics[".apps.latency.p99"], "99");
+
+        // Only gauges are kept across flushes
+        let metrics = statsd.flush(2);
+        assert_eq!(metrics, vec![(".apps.queue".to_string(), "7".to_string(), 2)]);
+    }
+}
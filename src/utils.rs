@@ -44,6 +44,7 @@ pub trait JsonParser {
     fn get_str<I: Index>(&self, index: I, default: &str) -> String;
     fn get_u64<I: Index>(&self, index: I, default: u64) -> u64;
     fn get_f64<I: Index>(&self, index: I, default: f64) -> f64;
+    fn get_duration<I: Index>(&self, index: I, default: f64) -> Duration;
 }
 
 #[allow(dead_code)]
@@ -64,6 +65,41 @@ pub fn now_ms() -> u128 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
 }
 
+// Cut the text to at most max bytes on a char boundary
+#[allow(dead_code)]
+pub fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max { return text; }
+    let mut end = max;
+    while !text.is_char_boundary(end) { end -= 1; }
+    &text[..end]
+}
+
+#[allow(dead_code)]
+#[inline]
+pub fn tidings(kind: &str, message: &str) -> String {
+    json!({ "type": kind, "message": message }).to_string()
+}
+
+#[allow(dead_code)]
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 { return "localhost".to_string(); }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).to_string()
+}
+
+// FNV-1a hash folded into 16 bits, stable across builds and platforms
+#[allow(dead_code)]
+pub fn hash_u16(key: &str) -> u16 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    ((hash >> 16) ^ (hash & 0xffff)) as u16
+}
+
 #[allow(dead_code)]
 impl JsonParser for Value {
     fn get_bool<I: Index>(&self, index: I, default: bool) -> bool {
@@ -98,6 +134,11 @@ impl JsonParser for Value {
             None => default,
         }
     }
+
+    // Seconds as duration, negative values are taken as zero
+    fn get_duration<I: Index>(&self, index: I, default: f64) -> Duration {
+        Duration::from_secs_f64(self.get_f64(index, default).max(0.0).min(std::u32::MAX as f64))
+    }
 }
 
 #[allow(dead_code)]
@@ -0,0 +1,270 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use regex::Regex;
+use crate::utils::{self, JsonParser, AsyncRes};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+use tokio::time;
+
+// Sample configuration
+//
+//  - watch:
+//      type: watch_expect
+//      address: 127.0.0.1:6379   # or socket: /run/redis/redis.sock
+//      timeout: 3                # seconds for each step, and for connecting
+//      ok_health: 100
+//      connect_failure_health: 0
+//      failure_health: 0         # health when a step fails, unless the step has its own
+//      steps:
+//        - send: "PING\r\n"
+//        - expect: "^\\+PONG\r\n"
+//          timeout: 1
+//          health: 20
+//        - send: "INFO memory\r\n"
+//        - expect: "used_memory:(?P<used_memory>\\d+)"
+//
+// Expected regex is matched against the data received since the last match. Named capture
+// groups of a matched expect step are collected as metrics.
+// Metric `.conversation_time_ms` is collected when all the steps succeed, in milliseconds.
+//
+
+// Max bytes to keep from the remote side while waiting for an expected pattern
+const MAX_BUFFER_SIZE: usize = 65536;
+
+enum Step {
+    Send(String),
+    Expect(Regex),
+    // Step with an invalid regex, which always fails
+    Invalid(String),
+}
+
+struct ExpectStep {
+    step: Step,
+    timeout: Duration,
+    health: u8,
+}
+
+pub struct ExpectWatch {
+    address: String,
+    socket: Option<String>,
+    timeout: Duration,
+    steps: Vec<ExpectStep>,
+    ok_health: u8,
+    connect_failure_health: u8,
+}
+
+impl ExpectWatch {
+    pub fn new(raw: &Value) -> ExpectWatch {
+        let timeout = raw.get_duration("timeout", 3.0);
+        let failure_health = raw.get_u64("failure_health", 0) as u8;
+        let mut steps = Vec::new();
+        for item in raw["steps"].as_array().unwrap_or(&Vec::new()).iter() {
+            let step = if let Some(data) = item["send"].as_str() {
+                Step::Send(data.to_string())
+            } else if let Some(pattern) = item["expect"].as_str() {
+                match Regex::new(pattern) {
+                    Ok(re) => Step::Expect(re),
+                    Err(e) => {
+                        error!("Invalid expect regex {} for expect watch, error: {}", pattern, e);
+                        Step::Invalid(pattern.to_string())
+                    }
+                }
+            } else {
+                error!("Expect watch step should have send or expect: {}", item);
+                continue;
+            };
+            steps.push(ExpectStep {
+                step,
+                timeout: item.get_duration("timeout", timeout.as_secs_f64()),
+                health: item.get_u64("health", failure_health as u64) as u8,
+            });
+        }
+        ExpectWatch {
+            address: raw.get_str("address", "127.0.0.1:80"),
+            socket: raw["socket"].as_str().map(|s| s.to_string()),
+            timeout,
+            steps,
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            connect_failure_health: raw.get_u64("connect_failure_health", 0) as u8,
+        }
+    }
+
+    pub async fn check(&self, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>, messages: &mut Vec<String>) -> AsyncRes {
+        let start = Instant::now();
+        let res = match self.socket {
+            Some(ref path) => match time::timeout(self.timeout, UnixStream::connect(path)).await {
+                Ok(Ok(stream)) => Ok(self.converse(stream, metrics).await),
+                Ok(Err(e)) => Err(format!("Failed to connect to {}, error: {}", path, e)),
+                Err(_) => Err(format!("Timed out connecting to {}", path)),
+            },
+            None => match time::timeout(self.timeout, TcpStream::connect(self.address.as_str())).await {
+                Ok(Ok(stream)) => Ok(self.converse(stream, metrics).await),
+                Ok(Err(e)) => Err(format!("Failed to connect to {}, error: {}", self.address, e)),
+                Err(_) => Err(format!("Timed out connecting to {}", self.address)),
+            },
+        };
+        *health_status = match res {
+            Ok(Ok(_)) => {
+                metrics.push((".conversation_time_ms".to_string(), format!("{:.3}", start.elapsed().as_secs_f64() * 1000.0), utils::now()));
+                self.ok_health
+            },
+            Ok(Err((health, info))) => {
+                warn!("{}", info);
+                messages.push(utils::tidings("expect", &info));
+                health
+            },
+            Err(info) => {
+                warn!("{}", info);
+                messages.push(utils::tidings("expect", &info));
+                self.connect_failure_health
+            },
+        };
+        Ok(())
+    }
+
+    // Walk through the steps, returns the health and the reason of the first failed step
+    async fn converse<S: AsyncRead + AsyncWrite + Unpin>(&self, mut stream: S, metrics: &mut Vec<(String, String, u64)>) -> Result<(), (u8, String)> {
+        let mut buffer = String::new();
+        let mut chunk = [0u8; 4096];
+        for (index, step) in self.steps.iter().enumerate() {
+            let fail = |reason: String| Err((step.health, format!("Step {} failed: {}", index + 1, reason)));
+            match step.step {
+                Step::Send(ref data) => {
+                    match time::timeout(step.timeout, stream.write_all(data.as_bytes())).await {
+                        Ok(Ok(_)) => {},
+                        Ok(Err(e)) => return fail(format!("error sending {:?}: {}", data, e)),
+                        Err(_) => return fail(format!("timed out sending {:?}", data)),
+                    }
+                },
+                Step::Invalid(ref pattern) => return fail(format!("invalid regex {}", pattern)),
+                Step::Expect(ref re) => {
+                    let deadline = time::Instant::now() + step.timeout;
+                    loop {
+                        if let Some(captures) = re.captures(&buffer) {
+                            let now = utils::now();
+                            for name in re.capture_names().filter_map(|name| name) {
+                                if let Some(value) = captures.name(name) {
+                                    metrics.push((format!(".{}", name), value.as_str().trim().to_string(), now));
+                                }
+                            }
+                            let end = captures.get(0).map(|m| m.end()).unwrap_or(0);
+                            buffer.drain(..end);
+                            break;
+                        }
+                        let size = match time::timeout_at(deadline, stream.read(&mut chunk)).await {
+                            Ok(Ok(0)) => return fail(format!("connection closed expecting /{}/, got {:?}", re, buffer)),
+                            Ok(Ok(size)) => size,
+                            Ok(Err(e)) => return fail(format!("error expecting /{}/: {}", re, e)),
+                            Err(_) => return fail(format!("timed out expecting /{}/, got {:?}", re, buffer)),
+                        };
+                        buffer.push_str(&String::from_utf8_lossy(&chunk[..size]));
+                        if buffer.len() > MAX_BUFFER_SIZE {
+                            let mut pos = buffer.len() - MAX_BUFFER_SIZE;
+                            while !buffer.is_char_boundary(pos) { pos += 1; }
+                            buffer.drain(..pos);
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    // Mock of a redis like line protocol
+    async fn mock_server() -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (r, mut w) = tokio::io::split(stream);
+                    w.write_all(b"+READY\r\n").await.unwrap();
+                    let mut lines = BufReader::new(r).lines();
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        let reply = match line.trim() {
+                            "PING" => "+PONG\r\n".to_string(),
+                            "INFO" => "# Memory\r\nused_memory:1024\r\nconnected_clients:3\r\n".to_string(),
+                            "SLEEP" => continue,
+                            _ => "-ERR unknown command\r\n".to_string(),
+                        };
+                        w.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    async fn check(addr: &str, steps: Value) -> (u8, Vec<(String, String, u64)>, Vec<String>) {
+        let watch = ExpectWatch::new(&json!({ "address": addr, "timeout": 0.5, "failure_health": 10, "steps": steps }));
+        let mut health = 0;
+        let mut metrics = Vec::new();
+        let mut messages = Vec::new();
+        watch.check(&mut health, &mut metrics, &mut messages).await.unwrap();
+        (health, metrics, messages)
+    }
+
+    #[tokio::test]
+    async fn test_expect_conversation() {
+        let addr = mock_server().await;
+        let (health, metrics, _) = check(&addr, json!([
+            { "expect": "^\\+READY\r\n" },
+            { "send": "PING\r\n" },
+            { "expect": "^\\+PONG" },
+            { "send": "INFO\r\n" },
+            { "expect": "used_memory:(?P<used_memory>\\d+)\r\nconnected_clients:(?P<clients>\\d+)" },
+        ])).await;
+        assert_eq!(health, 100);
+        assert_eq!(metrics[0].0, ".used_memory");
+        assert_eq!(metrics[0].1, "1024");
+        assert_eq!(metrics[1].1, "3");
+
+        // Health comes from the first failed step
+        let (health, _, messages) = check(&addr, json!([
+            { "send": "BOGUS\r\n" },
+            { "expect": "\\+OK", "health": 30 },
+            { "expect": "never", "health": 40 },
+        ])).await;
+        assert_eq!(health, 30);
+        assert!(messages[0].contains("Step 2 failed"));
+
+        // Timeout of a step
+        let (health, _, _) = check(&addr, json!([{ "send": "SLEEP\r\n" }, { "expect": "\\+PONG" }])).await;
+        assert_eq!(health, 10);
+
+        // Connection failure
+        let (health, _, _) = check("127.0.0.1:1", json!([])).await;
+        assert_eq!(health, 0);
+    }
+}
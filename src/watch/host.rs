@@ -0,0 +1,367 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser, AsyncRes};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::task;
+
+// Sample configuration
+//
+//  - watch:
+//      type: watch_host
+//      mounts: ["/", "/data"]
+//      interfaces: ["eth0"]          # all interfaces except lo if not specified
+//      ok_health: 100
+//      thresholds:
+//        - metric: cpu.usage
+//          above: 90
+//          health: 50
+//        - metric: disk.root.used_percent
+//          above: 95
+//          health: 10
+//        - metric: memory.available_percent
+//          below: 5
+//          health: 20
+//
+// Metrics collected (cpu and network rates start from the second check):
+//   .cpu.usage .cpu.user .cpu.system .cpu.iowait                    in percent
+//   .memory.total .memory.available .memory.used_percent .memory.available_percent
+//   .memory.swap_used_percent
+//   .network.<iface>.rx_bytes_per_sec .network.<iface>.tx_bytes_per_sec
+//   .network.<iface>.rx_errors .network.<iface>.tx_errors
+//   .disk.<mount>.used_percent .disk.<mount>.free_bytes .disk.<mount>.inodes_used_percent
+//
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpuTimes {
+    pub user: u64,
+    pub nice: u64,
+    pub system: u64,
+    pub idle: u64,
+    pub iowait: u64,
+    pub irq: u64,
+    pub softirq: u64,
+    pub steal: u64,
+}
+
+impl CpuTimes {
+    pub fn total(&self) -> u64 {
+        self.user + self.nice + self.system + self.idle + self.iowait + self.irq + self.softirq + self.steal
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NetCounters {
+    pub rx_bytes: u64,
+    pub rx_errors: u64,
+    pub tx_bytes: u64,
+    pub tx_errors: u64,
+}
+
+pub struct HostThreshold {
+    metric: String,
+    above: Option<f64>,
+    below: Option<f64>,
+    health: u8,
+}
+
+struct HostState {
+    cpu: Option<CpuTimes>,
+    net: HashMap<String, NetCounters>,
+    last_check: Option<Instant>,
+}
+
+pub struct HostWatch {
+    proc_root: String,
+    mounts: Vec<String>,
+    interfaces: Vec<String>,
+    thresholds: Vec<HostThreshold>,
+    ok_health: u8,
+    state: Mutex<HostState>,
+}
+
+// Parse the aggregated cpu line of /proc/stat
+pub fn parse_stat(raw: &str) -> Option<CpuTimes> {
+    for line in raw.lines() {
+        let mut tokens = line.split_whitespace();
+        if tokens.next() != Some("cpu") { continue; }
+        let values: Vec<u64> = tokens.map(|v| v.parse().unwrap_or(0)).collect();
+        if values.len() < 4 { return None; }
+        let get = |i: usize| values.get(i).cloned().unwrap_or(0);
+        return Some(CpuTimes {
+            user: get(0),
+            nice: get(1),
+            system: get(2),
+            idle: get(3),
+            iowait: get(4),
+            irq: get(5),
+            softirq: get(6),
+            steal: get(7),
+        });
+    }
+    None
+}
+
+// Parse /proc/meminfo into bytes
+pub fn parse_meminfo(raw: &str) -> HashMap<String, u64> {
+    let mut info = HashMap::new();
+    for line in raw.lines() {
+        let mut tokens = line.splitn(2, ':');
+        let key = tokens.next().unwrap_or("").trim();
+        let mut value = tokens.next().unwrap_or("").split_whitespace();
+        if let Some(Ok(number)) = value.next().map(|v| v.parse::<u64>()) {
+            let scale = if value.next() == Some("kB") { 1024 } else { 1 };
+            info.insert(key.to_string(), number * scale);
+        }
+    }
+    info
+}
+
+// Parse /proc/net/dev counters per interface
+pub fn parse_net_dev(raw: &str) -> Vec<(String, NetCounters)> {
+    let mut items = Vec::new();
+    for line in raw.lines() {
+        let mut tokens = line.splitn(2, ':');
+        let name = tokens.next().unwrap_or("").trim();
+        let data = match tokens.next() {
+            Some(data) => data,
+            None => continue,
+        };
+        let values: Vec<u64> = data.split_whitespace().map(|v| v.parse().unwrap_or(0)).collect();
+        if values.len() < 16 { continue; }
+        items.push((name.to_string(), NetCounters {
+            rx_bytes: values[0],
+            rx_errors: values[2],
+            tx_bytes: values[8],
+            tx_errors: values[10],
+        }));
+    }
+    items
+}
+
+// Metric path segment of a mount point, `/` as root and `/data/db` as data_db
+pub fn mount_name(mount: &str) -> String {
+    let name = mount.trim_matches('/').replace('/', "_").replace('.', "_");
+    if name.is_empty() { "root".to_string() } else { name }
+}
+
+fn percent(part: f64, total: f64) -> f64 {
+    if total > 0.0 { part * 100.0 / total } else { 0.0 }
+}
+
+impl HostWatch {
+    pub fn new(raw: &Value) -> HostWatch {
+        let mut mounts = Vec::new();
+        if let Some(items) = raw["mounts"].as_array() {
+            for item in items.iter() {
+                if let Some(mount) = item.as_str() { mounts.push(mount.to_string()); }
+            }
+        }
+        let mut interfaces = Vec::new();
+        if let Some(items) = raw["interfaces"].as_array() {
+            for item in items.iter() {
+                if let Some(iface) = item.as_str() { interfaces.push(iface.to_string()); }
+            }
+        }
+        let mut thresholds = Vec::new();
+        if let Some(items) = raw["thresholds"].as_array() {
+            for item in items.iter() {
+                thresholds.push(HostThreshold {
+                    metric: item.get_str("metric", ""),
+                    above: item["above"].as_f64(),
+                    below: item["below"].as_f64(),
+                    health: item.get_u64("health", 0) as u8,
+                });
+            }
+        }
+        HostWatch {
+            proc_root: raw.get_str("proc_root", "/proc"),
+            mounts,
+            interfaces,
+            thresholds,
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            state: Mutex::new(HostState {
+                cpu: None,
+                net: HashMap::new(),
+                last_check: None,
+            }),
+        }
+    }
+
+    pub async fn check(self: Arc<Self>, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>) -> AsyncRes {
+        // Reading /proc and statvfs of a stuck mount can block, keep them off the runtime threads
+        let watch = self.clone();
+        let mut values = task::spawn_blocking(move || {
+            let mut values: Vec<(String, f64)> = Vec::new();
+            watch.collect(&mut values);
+            values
+        }).await?;
+
+        *health_status = self.ok_health;
+        for threshold in self.thresholds.iter() {
+            for (name, value) in values.iter() {
+                if *name != threshold.metric { continue; }
+                let breached = threshold.above.map_or(false, |above| *value > above) ||
+                    threshold.below.map_or(false, |below| *value < below);
+                if breached && threshold.health < *health_status {
+                    *health_status = threshold.health;
+                }
+            }
+        }
+
+        let now = utils::now();
+        for (name, value) in values.drain(..) {
+            metrics.push((format!(".{}", name), value.to_string(), now));
+        }
+        Ok(())
+    }
+
+    fn collect(&self, values: &mut Vec<(String, f64)>) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = state.last_check.map(|last| now.duration_since(last).as_secs_f64());
+        state.last_check = Some(now);
+
+        // Cpu usage since last check
+        match fs::read_to_string(format!("{}/stat", self.proc_root)) {
+            Ok(raw) => {
+                if let Some(cpu) = parse_stat(&raw) {
+                    if let Some(ref last) = state.cpu {
+                        let total = cpu.total().saturating_sub(last.total()) as f64;
+                        let idle = (cpu.idle + cpu.iowait).saturating_sub(last.idle + last.iowait) as f64;
+                        values.push(("cpu.usage".to_string(), percent(total - idle, total)));
+                        values.push(("cpu.user".to_string(), percent((cpu.user + cpu.nice).saturating_sub(last.user + last.nice) as f64, total)));
+                        values.push(("cpu.system".to_string(), percent(cpu.system.saturating_sub(last.system) as f64, total)));
+                        values.push(("cpu.iowait".to_string(), percent(cpu.iowait.saturating_sub(last.iowait) as f64, total)));
+                    }
+                    state.cpu = Some(cpu);
+                }
+            },
+            Err(e) => error!("Failed to read {}/stat, error: {}", self.proc_root, e),
+        }
+
+        // Memory usage
+        match fs::read_to_string(format!("{}/meminfo", self.proc_root)) {
+            Ok(raw) => {
+                let info = parse_meminfo(&raw);
+                let total = *info.get("MemTotal").unwrap_or(&0) as f64;
+                let available = *info.get("MemAvailable").unwrap_or(&0) as f64;
+                let swap_total = *info.get("SwapTotal").unwrap_or(&0) as f64;
+                let swap_free = *info.get("SwapFree").unwrap_or(&0) as f64;
+                values.push(("memory.total".to_string(), total));
+                values.push(("memory.available".to_string(), available));
+                values.push(("memory.used_percent".to_string(), percent(total - available, total)));
+                values.push(("memory.available_percent".to_string(), percent(available, total)));
+                values.push(("memory.swap_used_percent".to_string(), percent(swap_total - swap_free, swap_total)));
+            },
+            Err(e) => error!("Failed to read {}/meminfo, error: {}", self.proc_root, e),
+        }
+
+        // Network throughput since last check
+        match fs::read_to_string(format!("{}/net/dev", self.proc_root)) {
+            Ok(raw) => {
+                for (name, counters) in parse_net_dev(&raw).drain(..) {
+                    if self.interfaces.is_empty() {
+                        if name == "lo" { continue; }
+                    } else if !self.interfaces.contains(&name) {
+                        continue;
+                    }
+                    if let (Some(last), Some(elapsed)) = (state.net.get(&name), elapsed) {
+                        if elapsed > 0.0 {
+                            values.push((format!("network.{}.rx_bytes_per_sec", name), counters.rx_bytes.saturating_sub(last.rx_bytes) as f64 / elapsed));
+                            values.push((format!("network.{}.tx_bytes_per_sec", name), counters.tx_bytes.saturating_sub(last.tx_bytes) as f64 / elapsed));
+                        }
+                    }
+                    values.push((format!("network.{}.rx_errors", name), counters.rx_errors as f64));
+                    values.push((format!("network.{}.tx_errors", name), counters.tx_errors as f64));
+                    state.net.insert(name, counters);
+                }
+            },
+            Err(e) => error!("Failed to read {}/net/dev, error: {}", self.proc_root, e),
+        }
+
+        // Disk usage of mounts
+        for mount in self.mounts.iter() {
+            let path = match CString::new(mount.as_str()) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+            if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+                error!("Failed to statvfs mount {}", mount);
+                continue;
+            }
+            let name = mount_name(mount);
+            let block = stat.f_frsize as f64;
+            let total = stat.f_blocks as f64 * block;
+            let free = stat.f_bfree as f64 * block;
+            let available = stat.f_bavail as f64 * block;
+            // Same as df, reserved blocks are not counted as available
+            values.push((format!("disk.{}.used_percent", name), percent(total - free, total - free + available)));
+            values.push((format!("disk.{}.free_bytes", name), available));
+            values.push((format!("disk.{}.inodes_used_percent", name), percent((stat.f_files - stat.f_ffree) as f64, stat.f_files as f64)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_proc_stat() {
+        let cpu = parse_stat(include_str!("../../tests/fixtures/proc/stat")).unwrap();
+        assert_eq!(cpu.user, 284764);
+        assert_eq!(cpu.idle, 8413551);
+        assert_eq!(cpu.iowait, 10923);
+        assert_eq!(cpu.total(), 284764 + 1261 + 79418 + 8413551 + 10923 + 3107);
+    }
+
+    #[test]
+    fn test_parse_proc_meminfo() {
+        let info = parse_meminfo(include_str!("../../tests/fixtures/proc/meminfo"));
+        assert_eq!(info["MemTotal"], 8041232 * 1024);
+        assert_eq!(info["MemAvailable"], 5230948 * 1024);
+        assert_eq!(info["HugePages_Total"], 0);
+    }
+
+    #[test]
+    fn test_parse_proc_net_dev() {
+        let items = parse_net_dev(include_str!("../../tests/fixtures/proc/net_dev"));
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[1].0, "eth0");
+        assert_eq!(items[1].1, NetCounters { rx_bytes: 3954019712, rx_errors: 3, tx_bytes: 987132264, tx_errors: 1 });
+        assert_eq!(items[2].0, "docker0");
+    }
+
+    #[test]
+    fn test_mount_name() {
+        assert_eq!(mount_name("/"), "root");
+        assert_eq!(mount_name("/data/db"), "data_db");
+    }
+}
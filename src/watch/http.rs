@@ -0,0 +1,251 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser, AsyncRes};
+use std::time::Instant;
+use regex::Regex;
+use reqwest::{Client, Method};
+
+// Sample configuration
+//
+//  - watch:
+//      type: watch_http
+//      url: http://127.0.0.1:8080/health
+//      method: GET
+//      headers:
+//        Authorization: "Bearer xxx"
+//      body: ""
+//      timeout: 10
+//      expect_status: [200, 204]      # any 2xx if not specified
+//      body_regex: "\"status\":\\s*\"up\""
+//      json_pointer: /status          # the pointer must exist in the json body
+//      json_value: up                 # and match the value if specified
+//      latency_thresholds:
+//        - above_ms: 500
+//          health: 60
+//        - above_ms: 2000
+//          health: 30
+//      ok_health: 100
+//      connect_failure_health: 0
+//      timeout_health: 0
+//      status_mismatch_health: 0
+//      body_mismatch_health: 0
+//
+// Metrics `.latency_ms` and `.status_code` are collected for every request.
+//
+
+pub struct LatencyThreshold {
+    above_ms: u64,
+    health: u8,
+}
+
+pub struct HttpWatch {
+    url: String,
+    method: Method,
+    headers: Vec<(String, String)>,
+    body: Option<String>,
+    client: Client,
+    expect_status: Vec<u16>,
+    body_regex: Option<Regex>,
+    json_pointer: Option<String>,
+    json_value: Option<Value>,
+    latency_thresholds: Vec<LatencyThreshold>,
+    ok_health: u8,
+    connect_failure_health: u8,
+    timeout_health: u8,
+    status_mismatch_health: u8,
+    body_mismatch_health: u8,
+}
+
+impl HttpWatch {
+    pub fn new(raw: &Value) -> HttpWatch {
+        let method = raw.get_str("method", "GET").to_uppercase();
+        let method = match Method::from_bytes(method.as_bytes()) {
+            Ok(method) => method,
+            Err(_) => {
+                error!("Invalid http method {} for http watch, will use GET instead", method);
+                Method::GET
+            }
+        };
+
+        let mut headers = Vec::new();
+        if let Some(items) = raw["headers"].as_object() {
+            for (key, value) in items.iter() {
+                if let Some(value) = value.as_str() {
+                    headers.push((key.clone(), value.to_string()));
+                }
+            }
+        }
+
+        let mut expect_status = Vec::new();
+        if let Some(codes) = raw["expect_status"].as_array() {
+            for code in codes.iter() {
+                if let Some(code) = code.as_u64() {
+                    expect_status.push(code as u16);
+                }
+            }
+        }
+
+        let body_regex = match raw["body_regex"].as_str() {
+            Some(pattern) => match Regex::new(pattern) {
+                Ok(re) => Some(re),
+                Err(e) => {
+                    error!("Invalid body regex {} for http watch, error: {}", pattern, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let mut latency_thresholds = Vec::new();
+        if let Some(items) = raw["latency_thresholds"].as_array() {
+            for item in items.iter() {
+                latency_thresholds.push(LatencyThreshold {
+                    above_ms: item.get_u64("above_ms", 0),
+                    health: item.get_u64("health", 0) as u8,
+                });
+            }
+        }
+        // Check the slowest threshold first
+        latency_thresholds.sort_by(|a, b| b.above_ms.cmp(&a.above_ms));
+
+        let timeout = raw.get_duration("timeout", 10.0);
+        let client = Client::builder().timeout(timeout).build().unwrap_or_else(|e| {
+            error!("Failed to build http client with timeout, error: {}", e);
+            Client::new()
+        });
+
+        HttpWatch {
+            url: raw.get_str("url", "http://127.0.0.1"),
+            method,
+            headers,
+            body: raw["body"].as_str().map(|s| s.to_string()),
+            client,
+            expect_status,
+            body_regex,
+            json_pointer: raw["json_pointer"].as_str().map(|s| s.to_string()),
+            json_value: if raw["json_value"].is_null() { None } else { Some(raw["json_value"].clone()) },
+            latency_thresholds,
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            connect_failure_health: raw.get_u64("connect_failure_health", 0) as u8,
+            timeout_health: raw.get_u64("timeout_health", 0) as u8,
+            status_mismatch_health: raw.get_u64("status_mismatch_health", 0) as u8,
+            body_mismatch_health: raw.get_u64("body_mismatch_health", 0) as u8,
+        }
+    }
+
+    pub async fn check(&self, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>) -> AsyncRes {
+        let mut request = self.client.request(self.method.clone(), &self.url);
+        for (key, value) in self.headers.iter() {
+            request = request.header(key.as_str(), value.as_str());
+        }
+        if let Some(ref body) = self.body {
+            request = request.body(body.clone());
+        }
+
+        let start = Instant::now();
+        let res = request.send().await;
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                metrics.push((".latency_ms".to_string(), start.elapsed().as_millis().to_string(), utils::now()));
+                if e.is_timeout() {
+                    warn!("Timed out requesting {}, error: {}", self.url, e);
+                    *health_status = self.timeout_health;
+                } else {
+                    warn!("Failed to request {}, error: {}", self.url, e);
+                    *health_status = self.connect_failure_health;
+                }
+                return Ok(());
+            }
+        };
+
+        let status = res.status().as_u16();
+        let body = res.text().await;
+        let latency = start.elapsed().as_millis() as u64;
+        let now = utils::now();
+        metrics.push((".latency_ms".to_string(), latency.to_string(), now));
+        metrics.push((".status_code".to_string(), status.to_string(), now));
+
+        let status_ok = if self.expect_status.is_empty() {
+            status >= 200 && status < 300
+        } else {
+            self.expect_status.contains(&status)
+        };
+        if !status_ok {
+            warn!("Unexpected status code {} from {}", status, self.url);
+            *health_status = self.status_mismatch_health;
+            return Ok(());
+        }
+
+        let body = match body {
+            Ok(body) => body,
+            Err(e) => {
+                if e.is_timeout() {
+                    warn!("Timed out reading body from {}, error: {}", self.url, e);
+                    *health_status = self.timeout_health;
+                } else {
+                    warn!("Failed to read body from {}, error: {}", self.url, e);
+                    *health_status = self.connect_failure_health;
+                }
+                return Ok(());
+            }
+        };
+        if !self.match_body(&body) {
+            warn!("Unexpected response body from {}", self.url);
+            *health_status = self.body_mismatch_health;
+            return Ok(());
+        }
+
+        *health_status = self.ok_health;
+        for threshold in self.latency_thresholds.iter() {
+            if latency > threshold.above_ms {
+                *health_status = threshold.health;
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn match_body(&self, body: &str) -> bool {
+        if let Some(ref re) = self.body_regex {
+            if !re.is_match(body) { return false; }
+        }
+        if let Some(ref pointer) = self.json_pointer {
+            let data: Value = match serde_json::from_str(body) {
+                Ok(data) => data,
+                Err(_) => return false,
+            };
+            match data.pointer(pointer) {
+                Some(value) => {
+                    if let Some(ref expected) = self.json_value {
+                        if value != expected { return false; }
+                    }
+                },
+                None => return false,
+            }
+        }
+        true
+    }
+}
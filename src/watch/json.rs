@@ -0,0 +1,143 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser};
+use super::tag_metric;
+
+// Sample output of a watch_json check program
+//
+// {
+//   "health": 100,
+//   "severity": 2,
+//   "message": "replication lag is growing",
+//   "metrics": [
+//     { "name": "replication.lag", "value": 12.5, "timestamp": 1577836800, "tags": { "replica": "db2" } },
+//     { "name": "connections", "value": 87 }
+//   ]
+// }
+//
+// `metrics` can also be a plain object like { "connections": 87 }.
+//
+
+pub fn parse_report(output: &[u8], health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>, messages: &mut Vec<String>) -> bool {
+    let report: Value = match serde_json::from_slice(output) {
+        Ok(report) => report,
+        Err(e) => {
+            error!("Failed to parse json report from check output, error: {}", e);
+            return false;
+        }
+    };
+    if !report.is_object() {
+        error!("Json report of check output should be an object, got: {}", report);
+        return false;
+    }
+
+    if let Some(health) = report["health"].as_u64() {
+        if health > 100 {
+            warn!("Health {} in json report is out of range, taken as 100", health);
+        }
+        *health_status = health.min(100) as u8;
+    }
+
+    if let Some(message) = report["message"].as_str() {
+        messages.push(json!({
+            "type": "report",
+            "severity": report.get_u64("severity", 0),
+            "message": message
+        }).to_string());
+    }
+
+    let now = utils::now();
+    if let Some(items) = report["metrics"].as_array() {
+        for item in items.iter() {
+            let name = match item["name"].as_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            let value = match metric_value(&item["value"]) {
+                Some(value) => value,
+                None => continue,
+            };
+            let mut tags = Vec::new();
+            if let Some(items) = item["tags"].as_object() {
+                for (key, value) in items.iter() {
+                    match value.as_str() {
+                        Some(value) => tags.push((key.clone(), value.to_string())),
+                        None => tags.push((key.clone(), value.to_string())),
+                    }
+                }
+            }
+            metrics.push((format!(".{}", tag_metric(name, &tags)), value, item.get_u64("timestamp", now)));
+        }
+    } else if let Some(items) = report["metrics"].as_object() {
+        for (name, value) in items.iter() {
+            if let Some(value) = metric_value(value) {
+                metrics.push((format!(".{}", name), value, now));
+            }
+        }
+    }
+    true
+}
+
+fn metric_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(n) => Some(n.to_string()),
+        Value::String(s) => Some(s.clone()),
+        Value::Bool(b) => Some((*b as u8).to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json_report() {
+        let output = br#"{
+            "health": 80, "severity": 3, "message": "lagging",
+            "metrics": [
+                { "name": "lag", "value": 12.5, "timestamp": 100, "tags": { "replica": "db2", "dc": "east" } },
+                { "name": "conns", "value": 87 },
+                { "value": 1 }
+            ]
+        }"#;
+        let mut health = 0;
+        let mut metrics = Vec::new();
+        let mut messages = Vec::new();
+        assert!(parse_report(output, &mut health, &mut metrics, &mut messages));
+        assert_eq!(health, 80);
+        assert_eq!(metrics.len(), 2);
+        assert_eq!(metrics[0], (".lag;dc=east;replica=db2".to_string(), "12.5".to_string(), 100));
+        assert_eq!(metrics[1].0, ".conns");
+        assert_eq!(messages.len(), 1);
+        let message: Value = serde_json::from_str(&messages[0]).unwrap();
+        assert_eq!(message["severity"], 3);
+        assert_eq!(message["message"], "lagging");
+
+        assert!(!parse_report(b"99", &mut health, &mut metrics, &mut messages));
+        assert!(parse_report(br#"{"health": 300}"#, &mut health, &mut metrics, &mut messages));
+        assert_eq!(health, 100);
+    }
+}
@@ -0,0 +1,281 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser, AsyncRes};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::sync::Mutex;
+use regex::Regex;
+
+// Sample configuration
+//
+//  - watch:
+//      type: watch_log
+//      path: /var/log/app/app.log
+//      from_start: false              # start from the end of the file by default
+//      max_read_bytes: 1048576        # max bytes to read per check
+//      max_messages: 10               # max lines to forward per check
+//      max_line_length: 4096          # forwarded lines are cut to this many bytes
+//      ok_health: 100
+//      rules:
+//        - pattern: "ERROR|FATAL"
+//          metric: errors_per_interval  # count matched lines into .errors_per_interval
+//        - pattern: "FATAL"
+//          health: 0                    # set health when the pattern appears
+//          forward: true                # forward the matched line as a message
+//
+// The file is followed across rotation and truncation, the offset is kept between checks.
+//
+
+pub struct LogRule {
+    pattern: Regex,
+    metric: Option<String>,
+    health: Option<u8>,
+    forward: bool,
+}
+
+struct LogState {
+    file: Option<File>,
+    inode: u64,
+    offset: u64,
+    // Trailing line without line break yet
+    partial: String,
+    init: bool,
+}
+
+pub struct LogWatch {
+    path: String,
+    from_start: bool,
+    max_read_bytes: u64,
+    max_messages: usize,
+    max_line_length: usize,
+    ok_health: u8,
+    rules: Vec<LogRule>,
+    state: Mutex<LogState>,
+}
+
+impl LogWatch {
+    pub fn new(raw: &Value) -> LogWatch {
+        let mut rules = Vec::new();
+        if let Some(items) = raw["rules"].as_array() {
+            for item in items.iter() {
+                let pattern = item.get_str("pattern", "");
+                match Regex::new(&pattern) {
+                    Ok(re) => rules.push(LogRule {
+                        pattern: re,
+                        metric: item["metric"].as_str().map(|s| s.to_string()),
+                        health: item["health"].as_u64().map(|h| h as u8),
+                        forward: item.get_bool("forward", false),
+                    }),
+                    Err(e) => error!("Invalid pattern {} for log watch, error: {}", pattern, e),
+                }
+            }
+        }
+        LogWatch {
+            path: raw.get_str("path", ""),
+            from_start: raw.get_bool("from_start", false),
+            max_read_bytes: raw.get_u64("max_read_bytes", 1024 * 1024),
+            max_messages: raw.get_u64("max_messages", 10) as usize,
+            // Keep the message frame below its 64K size limit, even with escaped characters
+            max_line_length: (raw.get_u64("max_line_length", 4096) as usize).min(8 * 1024),
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            rules,
+            state: Mutex::new(LogState {
+                file: None,
+                inode: 0,
+                offset: 0,
+                partial: String::new(),
+                init: true,
+            }),
+        }
+    }
+
+    pub async fn check(&self, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>, messages: &mut Vec<String>) -> AsyncRes {
+        let lines = self.read_lines();
+        *health_status = self.ok_health;
+
+        let mut counts = vec![0u64; self.rules.len()];
+        for line in lines.iter() {
+            for (index, rule) in self.rules.iter().enumerate() {
+                if !rule.pattern.is_match(line) { continue; }
+                counts[index] += 1;
+                if let Some(health) = rule.health {
+                    if health < *health_status { *health_status = health; }
+                }
+                if rule.forward {
+                    if messages.len() < self.max_messages {
+                        messages.push(json!({
+                            "type": "log",
+                            "path": self.path,
+                            "pattern": rule.pattern.as_str(),
+                            "message": utils::truncate(line, self.max_line_length)
+                        }).to_string());
+                    }
+                }
+            }
+        }
+
+        let now = utils::now();
+        for (index, rule) in self.rules.iter().enumerate() {
+            if let Some(ref metric) = rule.metric {
+                metrics.push((format!(".{}", metric), counts[index].to_string(), now));
+            }
+        }
+        Ok(())
+    }
+
+    // Read the complete lines appended since last check
+    pub fn read_lines(&self) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut lines = Vec::new();
+        let mut data = Vec::new();
+
+        let meta = fs::metadata(&self.path).ok();
+        let rotated = match meta {
+            Some(ref meta) => state.file.is_none() || meta.ino() != state.inode,
+            None => false,
+        };
+
+        if rotated {
+            // Drain the rotated file before switching to the new one, its last line is complete
+            if let Some(ref mut file) = state.file {
+                let _ = file.take(self.max_read_bytes).read_to_end(&mut data);
+                let mut text = std::mem::replace(&mut state.partial, String::new());
+                text.push_str(&String::from_utf8_lossy(&data));
+                lines = split_lines(&text);
+                if lines.last().map_or(false, |line| line.is_empty()) { lines.pop(); }
+            }
+            match File::open(&self.path) {
+                Ok(mut file) => {
+                    let meta = meta.unwrap();
+                    let mut offset = 0;
+                    // Skip existing content on the first open only
+                    if state.init && !self.from_start {
+                        offset = meta.len();
+                        let _ = file.seek(SeekFrom::Start(offset));
+                    }
+                    if !state.init { info!("Log file {} rotated, following the new file", self.path); }
+                    state.file = Some(file);
+                    state.inode = meta.ino();
+                    state.offset = offset;
+                },
+                Err(e) => {
+                    error!("Failed to open log file {}, error: {}", self.path, e);
+                    state.file = None;
+                }
+            }
+        } else if let Some(ref meta) = meta {
+            if meta.len() < state.offset {
+                // Truncated in place
+                info!("Log file {} truncated, reading from the start", self.path);
+                if let Some(ref mut file) = state.file {
+                    let _ = file.seek(SeekFrom::Start(0));
+                }
+                state.offset = 0;
+                state.partial.clear();
+            }
+        }
+        // A file showing up after the first check is read from the start
+        state.init = false;
+
+        let limit = self.max_read_bytes.saturating_sub(data.len() as u64);
+        let mut data = Vec::new();
+        if let Some(ref mut file) = state.file {
+            let _ = file.take(limit).read_to_end(&mut data);
+        }
+        state.offset += data.len() as u64;
+
+        let mut text = std::mem::replace(&mut state.partial, String::new());
+        text.push_str(&String::from_utf8_lossy(&data));
+        lines.extend(split_lines(&text));
+        state.partial = lines.pop().unwrap_or_default();
+        lines
+    }
+}
+
+fn split_lines(text: &str) -> Vec<String> {
+    text.split('\n').map(|line| line.trim_end_matches('\r').to_string()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::fs::OpenOptions;
+
+    fn append(path: &std::path::Path, data: &str) {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).unwrap();
+        file.write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_follow_log_file() {
+        let dir = std::env::temp_dir().join(format!("nw-log-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        append(&path, "old line\n");
+
+        let watch = LogWatch::new(&json!({ "path": path.to_str().unwrap() }));
+        assert!(watch.read_lines().is_empty());
+
+        append(&path, "line 1\nline 2\npart");
+        assert_eq!(watch.read_lines(), vec!["line 1", "line 2"]);
+        append(&path, "ial\n");
+        assert_eq!(watch.read_lines(), vec!["partial"]);
+
+        // Rotation
+        append(&path, "before rotation\n");
+        fs::rename(&path, dir.join("app.log.1")).unwrap();
+        append(&path, "after rotation\n");
+        assert_eq!(watch.read_lines(), vec!["before rotation", "after rotation"]);
+        append(&path, "unfinished");
+        fs::rename(&path, dir.join("app.log.2")).unwrap();
+        append(&path, "next\n");
+        assert_eq!(watch.read_lines(), vec!["unfinished", "next"]);
+
+        // Truncation
+        fs::write(&path, "").unwrap();
+        append(&path, "new\n");
+        assert_eq!(watch.read_lines(), vec!["new"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_log_file_created_later() {
+        let dir = std::env::temp_dir().join(format!("nw-log-late-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let watch = LogWatch::new(&json!({ "path": path.to_str().unwrap() }));
+        assert!(watch.read_lines().is_empty());
+        append(&path, "first\n");
+        assert_eq!(watch.read_lines(), vec!["first"]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,47 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+// Native watches run inside the ranger itself instead of forking a check program
+// for every interval. Each watch parses its own section of the target `watch` config.
+
+pub mod tcp;
+pub mod http;
+pub mod json;
+pub mod nagios;
+pub mod host;
+pub mod process;
+pub mod log;
+pub mod prometheus;
+pub mod passive;
+pub mod expect;
+
+// Encode tags into the metric name with graphite tag syntax: name;tag1=value1;tag2=value2
+pub fn tag_metric(name: &str, tags: &[(String, String)]) -> String {
+    let mut tags = tags.to_vec();
+    tags.sort();
+    let mut path = name.to_string();
+    for (key, value) in tags.iter() {
+        path.push_str(&format!(";{}={}", key, value));
+    }
+    path
+}
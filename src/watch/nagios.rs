@@ -0,0 +1,279 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser};
+use crate::watch::host::mount_name;
+
+// Sample configuration
+//
+//  - watch:
+//      type: watch_nagios
+//      prog: /usr/lib/nagios/plugins/check_disk
+//      args: ["-w", "20%", "-c", "10%", "-p", "/"]
+//      ok_health: 100
+//      warning_health: 60
+//      critical_health: 0
+//      unknown_health: 30
+//
+// Plugin output follows the nagios plugin api:
+//
+// DISK OK - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968
+// / 15272 MB (77%);
+// /boot 68 MB (69%); | /boot=68MB;88;93;0;98
+//
+// Perfdata is collected as metrics `.label`, `.label.warn`, `.label.crit`, `.label.min`
+// and `.label.max`, with time normalized into seconds and size into bytes. Labels are turned
+// into a single path segment, mount points like `/boot` become `boot` and `/` becomes `root`.
+//
+
+pub struct PerfData {
+    pub label: String,
+    pub value: String,
+    pub uom: String,
+    pub warn: Option<String>,
+    pub crit: Option<String>,
+    pub min: Option<String>,
+    pub max: Option<String>,
+}
+
+impl PerfData {
+    // Normalize a value or threshold to base units, seconds for time and bytes for size
+    pub fn normalize(&self, value: &str) -> Option<String> {
+        let value = value.parse::<f64>().ok()?;
+        let scale = match self.uom.as_str() {
+            "ms" => 1e-3,
+            "us" => 1e-6,
+            "KB" => 1024.0,
+            "MB" => 1024.0 * 1024.0,
+            "GB" => 1024.0 * 1024.0 * 1024.0,
+            "TB" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+            _ => 1.0,
+        };
+        Some((value * scale).to_string())
+    }
+}
+
+pub struct NagiosWatch {
+    ok_health: u8,
+    warning_health: u8,
+    critical_health: u8,
+    unknown_health: u8,
+}
+
+impl NagiosWatch {
+    pub fn new(raw: &Value) -> NagiosWatch {
+        NagiosWatch {
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            warning_health: raw.get_u64("warning_health", 60) as u8,
+            critical_health: raw.get_u64("critical_health", 0) as u8,
+            unknown_health: raw.get_u64("unknown_health", 30) as u8,
+        }
+    }
+
+    pub fn report(&self, code: Option<i32>, output: &[u8], health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>, messages: &mut Vec<String>) {
+        let (status, health) = match code {
+            Some(0) => ("OK", self.ok_health),
+            Some(1) => ("WARNING", self.warning_health),
+            Some(2) => ("CRITICAL", self.critical_health),
+            _ => ("UNKNOWN", self.unknown_health),
+        };
+        *health_status = health;
+
+        let output = String::from_utf8_lossy(output);
+        let (text, perfdata) = split_output(&output);
+        if health != self.ok_health {
+            messages.push(json!({
+                "type": "nagios",
+                "status": status,
+                "message": text
+            }).to_string());
+        }
+
+        let now = utils::now();
+        for perf in parse_perfdata(&perfdata).iter() {
+            let path = format!(".{}", perf.label);
+            if let Some(value) = perf.normalize(&perf.value) {
+                metrics.push((path.clone(), value, now));
+            }
+            for (name, value) in [("warn", &perf.warn), ("crit", &perf.crit), ("min", &perf.min), ("max", &perf.max)].iter() {
+                // Thresholds can be ranges like 10:20 or @10:20, only plain numbers are collected
+                if let Some(value) = value.as_ref().and_then(|v| perf.normalize(v)) {
+                    metrics.push((format!("{}.{}", path, name), value, now));
+                }
+            }
+        }
+    }
+}
+
+// Split plugin output into the text output and the perfdata.
+// Perfdata follows the first `|` of the first line, and the first `|` of the long text.
+pub fn split_output(output: &str) -> (String, String) {
+    let mut lines = output.lines();
+    let first = lines.next().unwrap_or("");
+    let mut tokens = first.splitn(2, '|');
+    let text = tokens.next().unwrap_or("").trim().to_string();
+    let mut perfdata = tokens.next().unwrap_or("").trim().to_string();
+
+    let rest: Vec<&str> = lines.collect();
+    let rest = rest.join("\n");
+    if let Some(index) = rest.find('|') {
+        perfdata.push(' ');
+        perfdata.push_str(&rest[index + 1..].replace('\n', " "));
+    }
+    (text, perfdata)
+}
+
+// Metric path segment of a perfdata label
+fn label_segment(label: &str) -> String {
+    let label = label.trim();
+    if label.starts_with('/') { return mount_name(label); }
+    label.chars().map(|c| if c.is_alphanumeric() || c == '_' || c == '-' { c } else { '_' }).collect()
+}
+
+// Parse perfdata items: 'label'=value[UOM];[warn];[crit];[min];[max]
+pub fn parse_perfdata(perfdata: &str) -> Vec<PerfData> {
+    let mut items = Vec::new();
+    let mut chars = perfdata.trim().chars().peekable();
+    loop {
+        while let Some(c) = chars.peek() {
+            if c.is_whitespace() { chars.next(); } else { break; }
+        }
+        if chars.peek().is_none() { break; }
+
+        // Label, can be quoted with single quotes and escaped with two single quotes
+        let mut label = String::new();
+        if chars.peek() == Some(&'\'') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('\'') => {
+                        if chars.peek() == Some(&'\'') {
+                            chars.next();
+                            label.push('\'');
+                        } else {
+                            break;
+                        }
+                    },
+                    Some(c) => label.push(c),
+                    None => break,
+                }
+            }
+            while let Some(c) = chars.peek() {
+                if *c == '=' || c.is_whitespace() { break; }
+                label.push(*c);
+                chars.next();
+            }
+        } else {
+            while let Some(c) = chars.peek() {
+                if *c == '=' || c.is_whitespace() { break; }
+                label.push(*c);
+                chars.next();
+            }
+        }
+
+        let mut data = String::new();
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            while let Some(c) = chars.peek() {
+                if c.is_whitespace() { break; }
+                data.push(*c);
+                chars.next();
+            }
+        }
+        if label.is_empty() || data.is_empty() {
+            warn!("Invalid nagios perfdata item: {}={}", label, data);
+            continue;
+        }
+
+        let mut fields = data.split(';');
+        let value = fields.next().unwrap_or("");
+        let split = value.find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+' || c == 'e' || c == 'E'))
+            .unwrap_or(value.len());
+        let (value, uom) = value.split_at(split);
+        if value.parse::<f64>().is_err() {
+            // Value could be `U` for undetermined
+            continue;
+        }
+        let mut next = || match fields.next() {
+            Some(field) if !field.is_empty() => Some(field.to_string()),
+            _ => None,
+        };
+        let warn = next();
+        let crit = next();
+        let min = next();
+        let max = next();
+        items.push(PerfData {
+            label: label_segment(&label),
+            value: value.to_string(),
+            uom: uom.to_string(),
+            warn,
+            crit,
+            min,
+            max,
+        });
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_perfdata() {
+        let items = parse_perfdata("time=0.06960s;;;0.000000 size=1024B 'free space'=56%;20;10;0;100 'it''s'=U bad 'a.b;c'=1 /var/log=2");
+        assert_eq!(items.len(), 5);
+        assert_eq!(items[0].label, "time");
+        assert_eq!(items[0].value, "0.06960");
+        assert_eq!(items[0].uom, "s");
+        assert_eq!(items[0].warn, None);
+        assert_eq!(items[0].min, Some("0.000000".to_string()));
+        assert_eq!(items[1].uom, "B");
+        assert_eq!(items[1].normalize(&items[1].value), Some("1024".to_string()));
+        assert_eq!(items[2].label, "free_space");
+        assert_eq!(items[2].warn, Some("20".to_string()));
+        assert_eq!(items[2].max, Some("100".to_string()));
+        assert_eq!(items[3].label, "a_b_c");
+        assert_eq!(items[4].label, "var_log");
+    }
+
+    #[test]
+    fn test_nagios_report() {
+        let output = b"DISK WARNING - free space: / 3326 MB (56%); | /=2643MB;5948;5958;0;5968\n/ 15272 MB (77%);\n/boot 68 MB (69%); | /boot=68MB;88;@93:95;0;98\n";
+        let watch = NagiosWatch::new(&json!({}));
+        let mut health = 0;
+        let mut metrics = Vec::new();
+        let mut messages = Vec::new();
+        watch.report(Some(1), output, &mut health, &mut metrics, &mut messages);
+        assert_eq!(health, 60);
+        assert_eq!(messages.len(), 1);
+        let names: Vec<&str> = metrics.iter().map(|m| m.0.as_str()).collect();
+        assert_eq!(names, vec![".root", ".root.warn", ".root.crit", ".root.min", ".root.max", ".boot", ".boot.warn", ".boot.min", ".boot.max"]);
+        assert_eq!(metrics[5].1, (68.0 * 1024.0 * 1024.0).to_string());
+        assert_eq!(metrics[6].1, (88.0 * 1024.0 * 1024.0).to_string());
+
+        watch.report(Some(3), b"", &mut health, &mut metrics, &mut messages);
+        assert_eq!(health, 30);
+    }
+}
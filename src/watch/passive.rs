@@ -0,0 +1,140 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::JsonParser;
+use super::json;
+
+// Sample configuration of a passive target, which is not polled but pushed to the passive
+// listener of the ranger by local applications
+//
+//    watch:
+//      type: watch_passive
+//      stale_after: 0       # seconds without any push to report stale_health, 0 to disable
+//      stale_health: 0
+//
+// Pushed lines, the target is the name or id of a passive target
+//
+//   backup 100 nightly backup finished
+//   {"target": "backup", "health": 100, "message": "nightly backup finished", "metrics": {"size": 1024}}
+//
+// The json line accepts the same fields as the report of watch_json.
+//
+
+pub struct PassiveWatch {
+    pub stale_after: u64,
+    pub stale_health: u8,
+}
+
+impl PassiveWatch {
+    pub fn new(raw: &Value) -> PassiveWatch {
+        PassiveWatch {
+            stale_after: raw.get_u64("stale_after", 0),
+            stale_health: raw.get_u64("stale_health", 0) as u8,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Push {
+    pub target: String,
+    pub health: Option<u8>,
+    pub metrics: Vec<(String, String, u64)>,
+    pub messages: Vec<String>,
+}
+
+pub fn parse_push(line: &str) -> Option<Push> {
+    let line = line.trim();
+    if line.is_empty() { return None; }
+    let mut push = Push {
+        target: String::new(),
+        health: None,
+        metrics: Vec::new(),
+        messages: Vec::new(),
+    };
+    if line.starts_with('{') {
+        let report: Value = match serde_json::from_str(line) {
+            Ok(report) => report,
+            Err(e) => {
+                warn!("Invalid json push {}, error: {}", line, e);
+                return None;
+            }
+        };
+        push.target = report.get_str("target", "");
+        if !report["health"].is_null() && report["health"].as_u64().map_or(true, |health| health > 100) {
+            warn!("Invalid health in push {}, expecting 0-100", line);
+            return None;
+        }
+        let mut health = 0;
+        if !json::parse_report(line.as_bytes(), &mut health, &mut push.metrics, &mut push.messages) {
+            return None;
+        }
+        if report["health"].is_u64() {
+            push.health = Some(health);
+        }
+    } else {
+        let mut tokens = line.splitn(3, char::is_whitespace);
+        push.target = tokens.next().unwrap_or("").to_string();
+        match tokens.next().map(|health| health.parse::<u8>()) {
+            Some(Ok(health)) if health <= 100 => push.health = Some(health),
+            _ => {
+                warn!("Invalid push {}, expecting: <target> <health 0-100> [message]", line);
+                return None;
+            }
+        }
+        if let Some(message) = tokens.next() {
+            let message = message.trim();
+            if !message.is_empty() {
+                push.messages.push(json!({ "type": "report", "severity": 0, "message": message }).to_string());
+            }
+        }
+    }
+    if push.target.is_empty() {
+        warn!("Target is missing in push {}", line);
+        return None;
+    }
+    Some(push)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_push() {
+        let push = parse_push("backup 100 nightly backup finished\n").unwrap();
+        assert_eq!(push.target, "backup");
+        assert_eq!(push.health, Some(100));
+        assert!(push.messages[0].contains("nightly backup finished"));
+
+        let push = parse_push(r#"{"target": "backup", "metrics": {"size": 1024}}"#).unwrap();
+        assert_eq!(push.health, None);
+        assert_eq!(push.metrics[0].0, ".size");
+
+        assert!(parse_push("backup").is_none());
+        assert!(parse_push("backup ok").is_none());
+        assert!(parse_push("backup 200").is_none());
+        assert!(parse_push(r#"{"target": "backup", "health": 300}"#).is_none());
+        assert!(parse_push(r#"{"health": 100}"#).is_none());
+    }
+}
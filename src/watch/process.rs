@@ -0,0 +1,254 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser, AsyncRes};
+use std::collections::HashMap;
+use std::fs;
+use std::process;
+use std::sync::Mutex;
+use regex::Regex;
+
+// Sample configuration, processes are matched by one of pid_file, name or cmdline
+//
+//  - watch:
+//      type: watch_process
+//      pid_file: /var/run/nginx.pid
+//      name: nginx                    # exact process name
+//      cmdline: "java .*kafka\\.Kafka" # regex against the full command line
+//      min_count: 1
+//      max_count: 4                   # no limit if not specified
+//      ok_health: 100
+//      count_mismatch_health: 0
+//
+// Metrics collected:
+//   .count
+//   .process.<pid>.rss_bytes .process.<pid>.cpu_seconds
+//   .process.<pid>.open_fds .process.<pid>.uptime_seconds
+//
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PidStat {
+    pub pid: u32,
+    pub comm: String,
+    pub utime: u64,
+    pub stime: u64,
+    pub starttime: u64,
+    pub rss_pages: u64,
+}
+
+pub enum ProcessMatcher {
+    PidFile(String),
+    Name(String),
+    Cmdline(Regex),
+}
+
+pub struct ProcessWatch {
+    proc_root: String,
+    matcher: Option<ProcessMatcher>,
+    min_count: u64,
+    max_count: Option<u64>,
+    ok_health: u8,
+    count_mismatch_health: u8,
+    // Start time of processes seen in last check by pid
+    seen: Mutex<Option<HashMap<u32, u64>>>,
+}
+
+// Parse /proc/<pid>/stat, comm is wrapped by the first `(` and the last `)`
+pub fn parse_pid_stat(raw: &str) -> Option<PidStat> {
+    let open = raw.find('(')?;
+    let close = raw.rfind(')')?;
+    if close < open { return None; }
+    let pid = raw[..open].trim().parse().ok()?;
+    let comm = raw[open + 1..close].to_string();
+    let fields: Vec<&str> = raw[close + 1..].split_whitespace().collect();
+    // Fields start from the 3rd field `state` of proc(5)
+    let get = |i: usize| -> Option<u64> { fields.get(i - 3)?.parse().ok() };
+    Some(PidStat {
+        pid,
+        comm,
+        utime: get(14)?,
+        stime: get(15)?,
+        starttime: get(22)?,
+        rss_pages: get(24)?,
+    })
+}
+
+impl ProcessWatch {
+    pub fn new(raw: &Value) -> ProcessWatch {
+        let matcher = if let Some(pid_file) = raw["pid_file"].as_str() {
+            Some(ProcessMatcher::PidFile(pid_file.to_string()))
+        } else if let Some(name) = raw["name"].as_str() {
+            Some(ProcessMatcher::Name(name.to_string()))
+        } else if let Some(pattern) = raw["cmdline"].as_str() {
+            match Regex::new(pattern) {
+                Ok(re) => Some(ProcessMatcher::Cmdline(re)),
+                Err(e) => {
+                    error!("Invalid cmdline regex {} for process watch, error: {}", pattern, e);
+                    None
+                }
+            }
+        } else {
+            error!("One of pid_file, name or cmdline should be specified for process watch");
+            None
+        };
+
+        ProcessWatch {
+            proc_root: raw.get_str("proc_root", "/proc"),
+            matcher,
+            min_count: raw.get_u64("min_count", 1),
+            max_count: raw["max_count"].as_u64(),
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            count_mismatch_health: raw.get_u64("count_mismatch_health", 0) as u8,
+            seen: Mutex::new(None),
+        }
+    }
+
+    pub async fn check(&self, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>, messages: &mut Vec<String>) -> AsyncRes {
+        let procs = self.find_processes();
+        let count = procs.len() as u64;
+        let in_range = count >= self.min_count && self.max_count.map_or(true, |max| count <= max);
+        *health_status = if in_range { self.ok_health } else { self.count_mismatch_health };
+
+        let now = utils::now();
+        metrics.push((".count".to_string(), count.to_string(), now));
+
+        let clock_ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) }.max(1) as f64;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+        let uptime = fs::read_to_string(format!("{}/uptime", self.proc_root)).ok()
+            .and_then(|raw| raw.split_whitespace().next().and_then(|v| v.parse::<f64>().ok()));
+
+        for stat in procs.iter() {
+            let path = format!(".process.{}", stat.pid);
+            metrics.push((format!("{}.rss_bytes", path), (stat.rss_pages * page_size).to_string(), now));
+            metrics.push((format!("{}.cpu_seconds", path), ((stat.utime + stat.stime) as f64 / clock_ticks).to_string(), now));
+            if let Ok(fds) = fs::read_dir(format!("{}/{}/fd", self.proc_root, stat.pid)) {
+                metrics.push((format!("{}.open_fds", path), fds.count().to_string(), now));
+            }
+            if let Some(uptime) = uptime {
+                let age = uptime - stat.starttime as f64 / clock_ticks;
+                metrics.push((format!("{}.uptime_seconds", path), age.max(0.0).to_string(), now));
+            }
+        }
+
+        // Detect restarts from start time changes of the processes
+        let current: HashMap<u32, u64> = procs.iter().map(|stat| (stat.pid, stat.starttime)).collect();
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(ref last) = *seen {
+            let gone: Vec<u32> = last.iter()
+                .filter(|(pid, start)| current.get(pid) != Some(start))
+                .map(|(pid, _)| *pid).collect();
+            let new: Vec<u32> = current.iter()
+                .filter(|(pid, start)| last.get(pid) != Some(start))
+                .map(|(pid, _)| *pid).collect();
+            if !gone.is_empty() && !new.is_empty() {
+                let info = format!("Process restarted, pids {:?} replaced by {:?}", gone, new);
+                info!("{}", info);
+                messages.push(utils::tidings("restart", &info));
+            } else if !gone.is_empty() {
+                messages.push(utils::tidings("exit", &format!("Process exited, pids {:?}", gone)));
+            }
+        }
+        *seen = Some(current);
+        Ok(())
+    }
+
+    fn find_processes(&self) -> Vec<PidStat> {
+        let mut procs = Vec::new();
+        let matcher = match self.matcher {
+            Some(ref matcher) => matcher,
+            None => return procs,
+        };
+
+        if let ProcessMatcher::PidFile(ref pid_file) = matcher {
+            let pid = fs::read_to_string(pid_file).ok().and_then(|raw| raw.trim().parse::<u32>().ok());
+            if let Some(stat) = pid.and_then(|pid| self.read_stat(pid)) {
+                procs.push(stat);
+            }
+            return procs;
+        }
+
+        let own_pid = process::id();
+        let entries = match fs::read_dir(&self.proc_root) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Failed to read {}, error: {}", self.proc_root, e);
+                return procs;
+            }
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let pid = match entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+            if pid == own_pid { continue; }
+            let matched = match matcher {
+                ProcessMatcher::Name(ref name) => {
+                    let comm = fs::read_to_string(format!("{}/{}/comm", self.proc_root, pid)).unwrap_or_default();
+                    comm.trim() == name || self.read_cmdline(pid).split(' ').next()
+                        .map_or(false, |arg0| arg0.rsplit('/').next() == Some(name.as_str()))
+                },
+                ProcessMatcher::Cmdline(ref re) => re.is_match(&self.read_cmdline(pid)),
+                _ => false,
+            };
+            if matched {
+                if let Some(stat) = self.read_stat(pid) {
+                    procs.push(stat);
+                }
+            }
+        }
+        procs.sort_by_key(|stat| stat.pid);
+        procs
+    }
+
+    fn read_stat(&self, pid: u32) -> Option<PidStat> {
+        let raw = fs::read_to_string(format!("{}/{}/stat", self.proc_root, pid)).ok()?;
+        parse_pid_stat(&raw)
+    }
+
+    fn read_cmdline(&self, pid: u32) -> String {
+        match fs::read(format!("{}/{}/cmdline", self.proc_root, pid)) {
+            Ok(raw) => String::from_utf8_lossy(&raw).trim_end_matches('\0').replace('\0', " "),
+            Err(_) => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pid_stat() {
+        let stat = parse_pid_stat(include_str!("../../tests/fixtures/proc/pid_stat")).unwrap();
+        assert_eq!(stat, PidStat {
+            pid: 4242,
+            comm: "kafka (broker) x".to_string(),
+            utime: 52341,
+            stime: 8812,
+            starttime: 123456,
+            rss_pages: 262144,
+        });
+        assert!(parse_pid_stat("12 (short) S 1").is_none());
+    }
+}
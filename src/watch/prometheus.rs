@@ -0,0 +1,355 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{self, JsonParser, AsyncRes};
+use crate::watch::tag_metric;
+use regex::Regex;
+use reqwest::Client;
+
+// Sample configuration
+//
+//  - watch:
+//      type: watch_prometheus
+//      url: http://127.0.0.1:9100/metrics   # or set prog/args to parse the script stdout
+//      timeout: 10
+//      labels: tags                          # tags or path
+//      include: "^node_(load|filesystem)"    # only collect matching metric names
+//      exclude: "_bucket$"
+//      ok_health: 100
+//      scrape_failure_health: 0
+//      health_rules:
+//        - series: node_load1
+//          above: 8
+//          health: 60
+//        - series: up
+//          labels:
+//            job: api
+//          below: 1
+//          health: 0
+//          missing_health: 0              # when no series matches
+//
+// With `labels: tags` a sample `http_requests_total{code="200",method="get"} 10` is collected
+// as `.http_requests_total;code=200;method=get`, with `labels: path` as
+// `.http_requests_total.200.get`, label values ordered by label name.
+//
+
+#[derive(Debug, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub timestamp: Option<u64>,
+}
+
+pub struct HealthRule {
+    series: String,
+    labels: Vec<(String, String)>,
+    above: Option<f64>,
+    below: Option<f64>,
+    health: u8,
+    missing_health: Option<u8>,
+}
+
+impl HealthRule {
+    fn matches(&self, sample: &Sample) -> bool {
+        sample.name == self.series && self.labels.iter().all(|label| sample.labels.contains(label))
+    }
+}
+
+pub struct PrometheusWatch {
+    pub url: Option<String>,
+    client: Client,
+    label_path: bool,
+    include: Option<Regex>,
+    exclude: Option<Regex>,
+    ok_health: u8,
+    scrape_failure_health: u8,
+    health_rules: Vec<HealthRule>,
+}
+
+fn get_regex(raw: &Value, key: &str) -> Option<Regex> {
+    match raw[key].as_str() {
+        Some(pattern) => match Regex::new(pattern) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                error!("Invalid {} regex {} for prometheus watch, error: {}", key, pattern, e);
+                None
+            }
+        },
+        None => None,
+    }
+}
+
+fn get_labels(raw: &Value) -> Vec<(String, String)> {
+    let mut labels = Vec::new();
+    if let Some(items) = raw.as_object() {
+        for (key, value) in items.iter() {
+            if let Some(value) = value.as_str() {
+                labels.push((key.clone(), value.to_string()));
+            }
+        }
+    }
+    labels
+}
+
+// Metric path segments can not contain dots, whitespace or tag separators
+fn path_segment(value: &str) -> String {
+    if value.is_empty() { return "_".to_string(); }
+    value.chars().map(|c| if c == '.' || c == ';' || c == '=' || c.is_whitespace() { '_' } else { c }).collect()
+}
+
+// Graphite tag values can not contain tag separators or whitespace, nor start with a tilde
+fn tag_value(value: &str) -> String {
+    let value: String = value.chars().map(|c| if c == ';' || c == '=' || c.is_whitespace() { '_' } else { c }).collect();
+    if value.starts_with('~') { value.replacen('~', "_", 1) } else { value }
+}
+
+impl PrometheusWatch {
+    pub fn new(raw: &Value) -> PrometheusWatch {
+        let mut health_rules = Vec::new();
+        if let Some(items) = raw["health_rules"].as_array() {
+            for item in items.iter() {
+                health_rules.push(HealthRule {
+                    series: item.get_str("series", ""),
+                    labels: get_labels(&item["labels"]),
+                    above: item["above"].as_f64(),
+                    below: item["below"].as_f64(),
+                    health: item.get_u64("health", 0) as u8,
+                    missing_health: item["missing_health"].as_u64().map(|h| h as u8),
+                });
+            }
+        }
+
+        let timeout = raw.get_duration("timeout", 10.0);
+        let client = Client::builder().timeout(timeout).build().unwrap_or_else(|e| {
+            error!("Failed to build http client with timeout, error: {}", e);
+            Client::new()
+        });
+
+        PrometheusWatch {
+            url: raw["url"].as_str().map(|s| s.to_string()),
+            client,
+            label_path: raw.get_str("labels", "tags") == "path",
+            include: get_regex(raw, "include"),
+            exclude: get_regex(raw, "exclude"),
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            scrape_failure_health: raw.get_u64("scrape_failure_health", 0) as u8,
+            health_rules,
+        }
+    }
+
+    // Scrape the metrics endpoint
+    pub async fn check(&self, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>) -> AsyncRes {
+        let url = match self.url {
+            Some(ref url) => url,
+            None => return Ok(()),
+        };
+        let body = match self.client.get(url).send().await {
+            Ok(res) => {
+                if !res.status().is_success() {
+                    warn!("Unexpected status code {} scraping {}", res.status(), url);
+                    *health_status = self.scrape_failure_health;
+                    return Ok(());
+                }
+                res.text().await
+            },
+            Err(e) => Err(e),
+        };
+        match body {
+            Ok(body) => self.report(&body, health_status, metrics),
+            Err(e) => {
+                warn!("Failed to scrape {}, error: {}", url, e);
+                *health_status = self.scrape_failure_health;
+            }
+        }
+        Ok(())
+    }
+
+    // Collect metrics and compute health status from exposition text
+    pub fn report(&self, text: &str, health_status: &mut u8, metrics: &mut Vec<(String, String, u64)>) {
+        let samples = parse_exposition(text);
+        let now = utils::now();
+
+        *health_status = self.ok_health;
+        for rule in self.health_rules.iter() {
+            let mut found = false;
+            for sample in samples.iter().filter(|s| rule.matches(s)) {
+                found = true;
+                let hit = rule.above.map(|v| sample.value > v).unwrap_or(false) ||
+                    rule.below.map(|v| sample.value < v).unwrap_or(false);
+                if hit && rule.health < *health_status {
+                    *health_status = rule.health;
+                }
+            }
+            if !found {
+                if let Some(health) = rule.missing_health {
+                    if health < *health_status { *health_status = health; }
+                }
+            }
+        }
+
+        for sample in samples.iter() {
+            if !sample.value.is_finite() { continue; }
+            if let Some(ref re) = self.include {
+                if !re.is_match(&sample.name) { continue; }
+            }
+            if let Some(ref re) = self.exclude {
+                if re.is_match(&sample.name) { continue; }
+            }
+            let name = if self.label_path {
+                let mut labels = sample.labels.clone();
+                labels.sort();
+                let mut path = format!(".{}", sample.name);
+                for (_, value) in labels.iter() {
+                    path.push('.');
+                    path.push_str(&path_segment(value));
+                }
+                path
+            } else {
+                let tags: Vec<(String, String)> = sample.labels.iter().map(|(key, value)| (path_segment(key), tag_value(value))).collect();
+                tag_metric(&format!(".{}", sample.name), &tags)
+            };
+            metrics.push((name, sample.value.to_string(), sample.timestamp.unwrap_or(now)));
+        }
+    }
+}
+
+// Parse the prometheus text exposition format, comments and type hints are ignored
+pub fn parse_exposition(text: &str) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') { continue; }
+        match parse_sample(line) {
+            Some(sample) => samples.push(sample),
+            None => warn!("Failed to parse prometheus sample: {}", line),
+        }
+    }
+    samples
+}
+
+fn parse_sample(line: &str) -> Option<Sample> {
+    let name_end = line.find(|c: char| c == '{' || c.is_whitespace())?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+    let mut labels = Vec::new();
+
+    if rest.starts_with('{') {
+        let mut chars = rest.char_indices().skip(1).peekable();
+        let mut key = String::new();
+        let mut end = None;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '}' => { end = Some(i); break; },
+                ',' | ' ' => {},
+                '=' => {
+                    // Quoted label value with escapes
+                    if chars.next()?.1 != '"' { return None; }
+                    let mut value = String::new();
+                    loop {
+                        match chars.next()?.1 {
+                            '\\' => match chars.next()?.1 {
+                                'n' => value.push('\n'),
+                                c => value.push(c),
+                            },
+                            '"' => break,
+                            c => value.push(c),
+                        }
+                    }
+                    labels.push((key.trim().to_string(), value));
+                    key.clear();
+                },
+                c => key.push(c),
+            }
+        }
+        rest = &rest[end? + 1..];
+    }
+
+    let mut tokens = rest.split_whitespace();
+    let value = match tokens.next()? {
+        "+Inf" => std::f64::INFINITY,
+        "-Inf" => std::f64::NEG_INFINITY,
+        value => value.parse::<f64>().ok()?,
+    };
+    // Timestamps are in milliseconds, negative ones fall back to the scrape time
+    let timestamp = match tokens.next() {
+        Some(ts) => Some(ts.parse::<i64>().ok()?).filter(|ts| *ts >= 0).map(|ts| ts as u64 / 1000),
+        None => None,
+    };
+    Some(Sample { name, labels, value, timestamp })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_exposition() {
+        let text = r#"
+# HELP http_requests_total The total number of HTTP requests.
+# TYPE http_requests_total counter
+http_requests_total{method="post",code="200"} 1027 1395066363000
+http_requests_total{method="post",code="400"}    3 1395066363000
+msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9
+metric_without_timestamp_and_labels 12.47
+http_request_duration_seconds_bucket{le="+Inf"} 144320
+"#;
+        let samples = parse_exposition(text);
+        assert_eq!(samples.len(), 5);
+        assert_eq!(samples[0], Sample {
+            name: "http_requests_total".to_string(),
+            labels: vec![("method".to_string(), "post".to_string()), ("code".to_string(), "200".to_string())],
+            value: 1027.0,
+            timestamp: Some(1395066363),
+        });
+        assert_eq!(samples[2].labels[0].1, "C:\\DIR\\FILE.TXT");
+        assert_eq!(samples[2].labels[1].1, "Cannot find file:\n\"FILE.TXT\"");
+        assert_eq!(samples[3].value, 12.47);
+        assert!(samples[3].labels.is_empty());
+        assert_eq!(samples[4].labels[0].1, "+Inf");
+
+        let watch = PrometheusWatch::new(&json!({
+            "labels": "path",
+            "health_rules": [{ "series": "http_requests_total", "labels": { "code": "400" }, "above": 0, "health": 50 }]
+        }));
+        let mut health = 0;
+        let mut metrics = Vec::new();
+        watch.report(text, &mut health, &mut metrics);
+        assert_eq!(health, 50);
+        assert_eq!(metrics[1].0, ".http_requests_total.400.post");
+        assert_eq!(metrics[1].2, 1395066363);
+
+        let watch = PrometheusWatch::new(&json!({ "labels": "tags" }));
+        let mut metrics = Vec::new();
+        watch.report(text, &mut health, &mut metrics);
+        assert_eq!(metrics[2].0, ".msdos_file_access_time_seconds;error=Cannot_find_file:_\"FILE.TXT\";path=C:\\DIR\\FILE.TXT");
+
+        let samples = parse_exposition("before_epoch{job=\"\"} 1 -1000\n");
+        assert_eq!(samples[0].timestamp, None);
+        let watch = PrometheusWatch::new(&json!({ "labels": "path" }));
+        let mut metrics = Vec::new();
+        watch.report("empty_label{job=\"\"} 1\n", &mut health, &mut metrics);
+        assert_eq!(metrics[0].0, ".empty_label._");
+    }
+}
@@ -0,0 +1,128 @@
+/*  MIT License
+
+Copyright (c) 2019 Stefan Liu - NightsWatch
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use serde_json::Value;
+use crate::utils::{JsonParser, AsyncRes};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time;
+
+// Sample configuration
+//
+//  - watch:
+//      type: watch_tcp
+//      address: 127.0.0.1:5432
+//      timeout: 3
+//      send: "PING\r\n"
+//      expect: "+PONG"
+//      ok_health: 100
+//      connect_failure_health: 0
+//      timeout_health: 0
+//      mismatch_health: 50
+//
+
+// Max bytes to read from the remote side while waiting for the expected banner
+const MAX_BANNER_SIZE: usize = 4096;
+
+pub enum TcpCheckError {
+    Connect,
+    Mismatch,
+}
+
+pub struct TcpWatch {
+    address: String,
+    timeout: Duration,
+    send: Option<String>,
+    expect: Option<String>,
+    ok_health: u8,
+    connect_failure_health: u8,
+    timeout_health: u8,
+    mismatch_health: u8,
+}
+
+impl TcpWatch {
+    pub fn new(raw: &Value) -> TcpWatch {
+        TcpWatch {
+            address: raw.get_str("address", "127.0.0.1:80"),
+            timeout: raw.get_duration("timeout", 3.0),
+            send: raw["send"].as_str().map(|s| s.to_string()),
+            expect: raw["expect"].as_str().map(|s| s.to_string()),
+            ok_health: raw.get_u64("ok_health", 100) as u8,
+            connect_failure_health: raw.get_u64("connect_failure_health", 0) as u8,
+            timeout_health: raw.get_u64("timeout_health", 0) as u8,
+            mismatch_health: raw.get_u64("mismatch_health", 0) as u8,
+        }
+    }
+
+    pub async fn check(&self, health_status: &mut u8) -> AsyncRes {
+        *health_status = match time::timeout(self.timeout, self.converse()).await {
+            Ok(Ok(_)) => self.ok_health,
+            Ok(Err(TcpCheckError::Connect)) => {
+                warn!("Failed to connect to {}", self.address);
+                self.connect_failure_health
+            },
+            Ok(Err(TcpCheckError::Mismatch)) => {
+                warn!("Unexpected response from {}, expecting: {:?}", self.address, self.expect);
+                self.mismatch_health
+            },
+            Err(_) => {
+                warn!("Timed out checking {} after {:?}", self.address, self.timeout);
+                self.timeout_health
+            },
+        };
+        Ok(())
+    }
+
+    async fn converse(&self) -> Result<(), TcpCheckError> {
+        let mut stream = match TcpStream::connect(self.address.as_str()).await {
+            Ok(stream) => stream,
+            Err(_) => return Err(TcpCheckError::Connect),
+        };
+
+        if let Some(ref send) = self.send {
+            if stream.write_all(send.as_bytes()).await.is_err() {
+                return Err(TcpCheckError::Connect);
+            }
+        }
+
+        if let Some(ref expect) = self.expect {
+            let mut banner = Vec::new();
+            let mut buf = [0u8; 512];
+            loop {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(size) => {
+                        banner.extend_from_slice(&buf[..size]);
+                        if String::from_utf8_lossy(&banner).contains(expect.as_str()) {
+                            return Ok(());
+                        }
+                        if banner.len() >= MAX_BANNER_SIZE { break; }
+                    }
+                }
+            }
+            return Err(TcpCheckError::Mismatch);
+        }
+        Ok(())
+    }
+}
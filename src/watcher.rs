@@ -32,6 +32,9 @@ use std::collections::HashMap;
 use std::error::Error;
 use crate::eval::*;
 use crate::dispatcher::*;
+use crate::nightfort::Couriers;
+use crate::dracarys::RangerCommand;
+use std::sync::Mutex;
 use crate::maester::Maester;
 
 pub struct WatcherState {
@@ -50,7 +53,8 @@ pub struct Watcher {
     app_map: Arc<RwLock<HashMap<String, Arc<Application>>>>,
     store: Arc<Store>,
     state: Arc<RwLock<WatcherState>>,
-    maester: Arc<Maester>,
+    pub maester: Arc<Maester>,
+    pub couriers: Arc<Mutex<Couriers>>,
     pub dispatcher: WatcherDispatcher,
     pub locker: Arc<NodePathLocker>,
     pub landing: Arc<RwLock<Landing>>,
@@ -72,6 +76,7 @@ impl Watcher {
             state: Arc::new(RwLock::new(state)),
             store: StoreProto::new(),
             maester: maester.clone(),
+            couriers: Couriers::new(),
             dispatcher: WatcherDispatcher::new(&landing, maester),
             locker: NodePathLockerProto::new(),
             landing: Arc::new(RwLock::new(landing)),
@@ -111,6 +116,44 @@ impl Watcher {
         None
     }
 
+    // Connected rangers with their identity and targets
+    pub fn list_rangers(&self) -> Value {
+        self.couriers.lock().unwrap().dump()
+    }
+
+    // Send a command to the ranger watching the leaf, returns the command sequence
+    pub fn send_ranger_command(&self, path: &String, command: RangerCommand) -> Result<u32, String> {
+        let node = match self.locate_node(path).and_then(|node| node.upgrade()) {
+            Some(node) => node,
+            None => return Err(format!("No node found for {}", path)),
+        };
+        let node_id = node.read().unwrap().id;
+        self.couriers.lock().unwrap().send_command(node_id, path, command)
+    }
+
+    pub fn retire_ranger(&self, ranger: &Weak<Node>) {
+        // Unlink from parents and drop the leaf node from store
+        if let Some(node) = ranger.upgrade() {
+            let (id, parents) = {
+                let leaf = node.read().unwrap();
+                (leaf.id, leaf.parents.clone())
+            };
+            for parent in parents.iter() {
+                if let Some(parent_node) = parent.upgrade() {
+                    let mut state = parent_node.write().unwrap();
+                    state.children.retain(|child| !child.ptr_eq(ranger));
+                }
+            }
+            let paths = node.read().unwrap().get_paths();
+            self.store.remove_node(&id);
+            for path in paths.iter() {
+                if let Some(app) = AppMeta::parse_app_name(path) {
+                    self.sig_app_init(&app);
+                }
+            }
+        }
+    }
+
     pub fn allocate_ranger(&self, name: &String, paths: &Vec<String>, raw: &Value) -> Option<Weak<Node>> {
         // Create new leaf node
         // Link to parents